@@ -0,0 +1,150 @@
+//! Benchmarks of the write path, making calls on a device bus backed by the mock HLAPI.
+//!
+//! The `write` group compares writing a call with the bus against the write path it replaced, which
+//! serialized every call into a fixed-size `ArrayVec` on the stack before writing it out. Both
+//! write to a transport which throws everything away, so that only encoding and writing count.
+//!
+//! Measured on x86_64, the bus is faster for a typical call and about as fast for one which only
+//! just fits:
+//!
+//! | Call size   | `arrayvec` | `bus`   |
+//! |-------------|-----------:|--------:|
+//! | 16 bytes    | 237 ns     | 178 ns  |
+//! | 3,900 bytes | 1.58 µs    | 1.57 µs |
+//!
+//! There are no numbers from the RISC-V target yet.
+//!
+//! Run them with `cargo bench --features mock`. To measure on the OC2 VM itself, build them for
+//! the RISC-V target with `./build-riscv.sh --benches --features mock --release`, copy the bench
+//! executable from `target/riscv64gc-unknown-linux-musl/release/deps` into the VM and run it with
+//! `--bench`.
+
+use arrayvec::ArrayVec;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use oc2_hlapi::bus::{DeviceBus, MAX_MESSAGE_SIZE};
+use oc2_hlapi::call::{Call, Invoke};
+use oc2_hlapi::mock::MockBus;
+use oc2_hlapi::transport::Transport;
+use oc2_hlapi::types::DeviceDescriptor;
+use std::io::{self, Read, Write};
+use uuid::Uuid;
+
+// A typical call, one which only just fits into a message, and one which is far too long.
+const SIZES: [usize; 3] = [16, 3_900, 65_536];
+
+/// A transport which accepts every write and never has anything to read.
+struct Sink;
+
+impl Read for Sink {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Sink {}
+
+/// The write path before calls were serialized straight into the bus' write buffer.
+fn write_with_arrayvec(transport: &mut Sink, call: &Call<Invoke<'_, ()>>) -> Result<(), ()> {
+    let mut buffer = ArrayVec::<u8, MAX_MESSAGE_SIZE>::new();
+
+    buffer.try_push(b'\0').map_err(drop)?;
+    serde_json::to_writer(&mut buffer, call).map_err(drop)?;
+    buffer.try_push(b'\0').map_err(drop)?;
+
+    transport.write_all(&buffer).map_err(drop)?;
+    transport.flush().map_err(drop)
+}
+
+fn setup() -> (MockBus, DeviceBus, Uuid) {
+    let mock = MockBus::new();
+    let id = Uuid::new_v4();
+
+    mock.add_device(
+        DeviceDescriptor {
+            device_id: id,
+            type_names: Box::new(["bench".into()]),
+        },
+        [],
+    );
+    mock.returns(id, "echo", ()).unwrap();
+
+    let bus = mock.bus();
+
+    (mock, bus, id)
+}
+
+fn calls(c: &mut Criterion) {
+    let (mock, bus, id) = setup();
+    let mut group = c.benchmark_group("call");
+
+    for size in SIZES {
+        let parameter = "x".repeat(size);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(size),
+            &parameter,
+            |b, parameter| {
+                b.iter(|| {
+                    let result = bus.call(Call::<Invoke<'_, ()>>::invoke(
+                        id,
+                        "echo",
+                        &[black_box(parameter)],
+                    ));
+
+                    mock.clear_calls();
+                    black_box(result)
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn writes(c: &mut Criterion) {
+    let bus = DeviceBus::with_transport(Sink);
+    let mut transport = Sink;
+    let id = Uuid::new_v4();
+    let mut group = c.benchmark_group("write");
+
+    for size in SIZES {
+        let parameter = "x".repeat(size);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::new("arrayvec", size),
+            &parameter,
+            |b, parameter| {
+                b.iter(|| {
+                    let call = Call::<Invoke<'_, ()>>::invoke;
+                    black_box(write_with_arrayvec(
+                        &mut transport,
+                        &call(id, "echo", &[black_box(parameter)]),
+                    ))
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("bus", size), &parameter, |b, parameter| {
+            b.iter(|| {
+                let call = Call::<Invoke<'_, ()>>::invoke;
+                black_box(bus.write_message(call(id, "echo", &[black_box(parameter)])))
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, calls, writes);
+criterion_main!(benches);
@@ -0,0 +1,526 @@
+//! An asynchronous device bus, for awaiting many HLAPI calls from one thread.
+
+use crate::bus::{decode_response, encode_message, Builder, FrameDecoder};
+use crate::call::{ApiCall, Call};
+use crate::device::RpcDevice;
+use crate::error::{Error, Result};
+use crate::response;
+use crate::trace;
+use crate::transport::{open_raw, Console};
+use erased_serde::Serialize as ErasedSerialize;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// An asynchronous bidirectional byte stream which HLAPI messages can be sent and received over.
+/// This is implemented for every tokio stream, such as `tokio::net::UnixStream`.
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + ?Sized> AsyncTransport for T {}
+
+/// A handle to an asynchronous device bus which HLAPI calls can be made over. This is the
+/// asynchronous counterpart of [`RpcBus`](crate::bus::RpcBus).
+pub trait AsyncRpcBus: Clone + Send + Sync + 'static {
+    /// Calls an RPC method, writing the call to the bus and then reading the response.
+    fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> CallFuture<T::Response>;
+
+    /// Finds a device or module by its RpcDevice identifier.
+    fn find<D: RpcDevice<Bus = Self>>(&self) -> impl Future<Output = Result<Option<D>>> + Send {
+        self.find_by_name(D::IDENTIFIER)
+    }
+
+    /// Finds a device or module by its name.
+    fn find_by_name<D: RpcDevice<Bus = Self>>(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<D>>> + Send {
+        async move {
+            let response::List(list) = self.call(Call::list()).await?;
+
+            let device = list
+                .iter()
+                .find(|&desc| {
+                    desc.type_names
+                        .iter()
+                        .any(|identifier| &**identifier == name)
+                })
+                .map(|desc| D::new(desc.device_id, self));
+
+            Ok(device)
+        }
+    }
+}
+
+/// An asynchronous device bus, driven by a tokio runtime.
+///
+/// Calls made from several tasks at once are queued up and sent one at a time, so every task can
+/// await its own calls without needing a thread of its own. A call which is cancelled or times out
+/// doesn't disturb later calls: if it was cancelled while being written, the rest of it is written
+/// before the next call, and its response is thrown away once it arrives.
+#[derive(Clone, Debug)]
+pub struct AsyncDeviceBus(Arc<Shared>);
+
+impl AsyncDeviceBus {
+    /// Creates a new asynchronous device bus at the specified path. This must be called from within
+    /// a tokio runtime.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Builder::new().open_async(path)
+    }
+
+    /// Creates a new asynchronous device bus which sends and receives messages over the given
+    /// transport.
+    pub fn with_transport<T: AsyncTransport + 'static>(transport: T) -> Self {
+        Builder::new().build_async(transport)
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) listening on the Unix socket at the
+    /// specified path.
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Builder::new().connect_async(path).await
+    }
+
+    /// Calls an RPC method, waiting for at most the bus' default timeout for the response.
+    pub fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> CallFuture<T::Response> {
+        self.call_with(call, self.0.timeout)
+    }
+
+    /// Calls an RPC method, failing with [`Error::Timeout`] if no response was received within the
+    /// given duration. This overrides the bus' default timeout. Time spent waiting for other calls
+    /// to finish counts towards the timeout.
+    pub fn call_with_timeout<T: ApiCall + Serialize>(
+        &self,
+        call: Call<T>,
+        timeout: Duration,
+    ) -> CallFuture<T::Response> {
+        self.call_with(call, Some(timeout))
+    }
+
+    /// Finds a device or module by its RpcDevice identifier.
+    pub async fn find<D: RpcDevice<Bus = Self>>(&self) -> Result<Option<D>> {
+        AsyncRpcBus::find(self).await
+    }
+
+    /// Finds a device or module by its name.
+    pub async fn find_by_name<D: RpcDevice<Bus = Self>>(&self, name: &str) -> Result<Option<D>> {
+        AsyncRpcBus::find_by_name(self, name).await
+    }
+
+    fn call_with<T: ApiCall + Serialize>(
+        &self,
+        call: Call<T>,
+        timeout: Option<Duration>,
+    ) -> CallFuture<T::Response> {
+        // The call is serialized right away, since its parameters borrow from the caller and can't
+        // be sent between threads.
+        let mut message = Vec::new();
+
+        match encode_message(&mut message, &call, self.0.max_message_size) {
+            Ok(()) => {
+                let span = trace::async_call(&call, &message);
+                let exchange =
+                    exchange(Arc::clone(&self.0), message, timeout, decode_response::<T>);
+
+                CallFuture::new(span.instrument(exchange))
+            }
+            Err(e) => CallFuture::new(async move { Err(e) }),
+        }
+    }
+}
+
+impl AsyncRpcBus for AsyncDeviceBus {
+    fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> CallFuture<T::Response> {
+        AsyncDeviceBus::call(self, call)
+    }
+}
+
+/// A future which resolves to the response of a call made on an asynchronous device bus.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct CallFuture<R>(Pin<Box<dyn Future<Output = Result<R>> + Send>>);
+
+impl<R> CallFuture<R> {
+    /// Wraps a future which resolves to the response of a call.
+    pub fn new<F>(future: F) -> Self
+    where
+        F: Future<Output = Result<R>> + Send + 'static,
+    {
+        Self(Box::pin(future))
+    }
+}
+
+impl<R> Future for CallFuture<R> {
+    type Output = Result<R>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl<R> Debug for CallFuture<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallFuture").finish_non_exhaustive()
+    }
+}
+
+// Kept separate from `AsyncDeviceBus::call_with` so that the future doesn't depend on the type of
+// the call, which usually borrows its parameters.
+async fn exchange<R>(
+    shared: Arc<Shared>,
+    message: Vec<u8>,
+    timeout: Option<Duration>,
+    decode: fn(&[u8]) -> Result<R>,
+) -> Result<R> {
+    let exchange = async {
+        let mut connection = shared.connection.lock().await;
+        connection.exchange(&message, decode).await
+    };
+
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| Error::Timeout)?,
+        None => exchange.await,
+    }
+}
+
+impl Builder {
+    /// Creates a new asynchronous device bus at the specified path. This must be called from within
+    /// a tokio runtime.
+    pub fn open_async<P: AsRef<Path>>(self, path: P) -> Result<AsyncDeviceBus> {
+        Ok(self.build_async(AsyncTty::open(path)?))
+    }
+
+    /// Connects a new asynchronous device bus to a [`Multiplexer`](crate::mux::Multiplexer)
+    /// listening on the Unix socket at the specified path.
+    pub async fn connect_async<P: AsRef<Path>>(self, path: P) -> Result<AsyncDeviceBus> {
+        Ok(self.build_async(UnixStream::connect(path).await?))
+    }
+
+    /// Creates a new asynchronous device bus which sends and receives messages over the given
+    /// transport.
+    pub fn build_async<T: AsyncTransport + 'static>(self, transport: T) -> AsyncDeviceBus {
+        AsyncDeviceBus(Arc::new(Shared {
+            connection: Mutex::new(AsyncConnection {
+                transport: Box::new(transport),
+                decoder: FrameDecoder::with_max_len(self.max_message_size),
+                write_buffer: Vec::new(),
+                read_buffer: vec![0; FrameDecoder::READ_SIZE].into_boxed_slice(),
+                pending_responses: 0,
+            }),
+            max_message_size: self.max_message_size,
+            timeout: self.timeout,
+        }))
+    }
+}
+
+/// The serial console that the OC2 VM exposes the HLAPI on, registered with the tokio runtime.
+///
+/// Like [`Tty`](crate::transport::Tty), the console gets back its previous terminal attributes
+/// when this is dropped.
+#[derive(Debug)]
+pub struct AsyncTty(AsyncFd<Console>);
+
+impl AsyncTty {
+    /// Opens the console at the specified path and puts it into raw, non-blocking mode. This must
+    /// be called from within a tokio runtime.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let console = open_raw(path.as_ref())?;
+        // SAFETY: `console` owns its file descriptor, so it stays open until the `AsyncFd` is
+        // dropped.
+        let fd = unsafe { AsyncFd::register(console) }.map_err(io::Error::from)?;
+
+        Ok(Self(fd))
+    }
+}
+
+impl AsyncRead for AsyncTty {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+
+            match guard.try_io(|console| (&console.get_ref().file).read(buf.initialize_unfilled()))
+            {
+                Ok(result) => {
+                    buf.advance(result?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncTty {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+
+            match guard.try_io(|console| (&console.get_ref().file).write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The asynchronous counterpart of `device::invoke`.
+#[doc(hidden)]
+#[inline(never)]
+pub fn invoke<B: AsyncRpcBus, R: DeserializeOwned + 'static>(
+    id: Uuid,
+    bus: &B,
+    method_name: &str,
+    params: &[&dyn ErasedSerialize],
+) -> impl Future<Output = Result<R>> + Send + 'static {
+    let call = bus.call(Call::invoke(id, method_name, params));
+
+    async move { call.await.map(|r| r.0) }
+}
+
+#[derive(Debug)]
+struct Shared {
+    connection: Mutex<AsyncConnection>,
+    max_message_size: usize,
+    timeout: Option<Duration>,
+}
+
+struct AsyncConnection {
+    transport: Box<dyn AsyncTransport>,
+    decoder: FrameDecoder,
+    // The part of a call which hasn't been written yet. This is only left over when a call is
+    // cancelled in the middle of being written, and is written before the next call so that the
+    // frame isn't torn.
+    write_buffer: Vec<u8>,
+    read_buffer: Box<[u8]>,
+    // The number of calls which have been written but whose response hasn't been read yet,
+    // including calls which were cancelled while waiting.
+    pending_responses: usize,
+}
+
+impl AsyncConnection {
+    async fn exchange<R>(&mut self, message: &[u8], decode: fn(&[u8]) -> Result<R>) -> Result<R> {
+        self.write_buffer.extend_from_slice(message);
+        self.pending_responses += 1;
+
+        if let Err(e) = self.flush_writes().await {
+            // The unsent rest of earlier cancelled calls stays buffered, since their responses are
+            // still counted. This call is dropped too if none of it was written, and is otherwise
+            // left for the next call to finish like a cancelled one.
+            let unwritten = self.write_buffer.len();
+
+            if unwritten >= message.len() {
+                self.write_buffer.truncate(unwritten - message.len());
+                self.pending_responses -= 1;
+            }
+
+            return Err(e);
+        }
+
+        loop {
+            match self.decoder.next_valid_frame(trace::garbage) {
+                Ok(None) => {}
+                // Responses arrive in the same order that calls were made, so any responses before
+                // this call's own belong to calls which were cancelled.
+                _ if self.pending_responses > 1 => {
+                    self.pending_responses -= 1;
+                    continue;
+                }
+                Ok(Some(frame)) => {
+                    trace::message_received(frame);
+                    self.pending_responses -= 1;
+                    return decode(frame);
+                }
+                Err(e) => {
+                    self.pending_responses -= 1;
+                    return Err(e);
+                }
+            }
+
+            let bytes_read = self.transport.read(&mut self.read_buffer).await?;
+
+            if bytes_read == 0 {
+                return Err(Error::ReadZero);
+            }
+
+            self.decoder.push(&self.read_buffer[..bytes_read]);
+        }
+    }
+
+    /// Writes every buffered call. Bytes are only removed from the buffer once they were written,
+    /// so this can be cancelled or fail and be picked up again by the next call.
+    async fn flush_writes(&mut self) -> Result<()> {
+        while !self.write_buffer.is_empty() {
+            match self.transport.write(&self.write_buffer).await {
+                Ok(0) => return Err(Error::Io(io::ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.transport.flush().await?;
+
+        Ok(())
+    }
+}
+
+impl Debug for AsyncConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncConnection")
+            .field("unwritten", &self.write_buffer.len())
+            .field("pending_responses", &self.pending_responses)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{duplex, DuplexStream};
+
+    /// A pipe whose writes fail while `fail` is set.
+    struct Flaky {
+        pipe: DuplexStream,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl AsyncRead for Flaky {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.pipe).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Flaky {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Poll::Ready(Err(io::Error::other("write failed")));
+            }
+
+            Pin::new(&mut self.pipe).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.pipe).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.pipe).poll_shutdown(cx)
+        }
+    }
+
+    /// Answers every call read from `bus_end` with an empty list, and every torn call with an
+    /// error.
+    fn answer_calls(mut bus_end: DuplexStream) {
+        tokio::spawn(async move {
+            let mut decoder = FrameDecoder::new();
+            let mut buffer = [0; 256];
+
+            loop {
+                let read = bus_end.read(&mut buffer).await.unwrap();
+                decoder.push(&buffer[..read]);
+
+                while let Some(frame) = decoder.next_unbounded_frame() {
+                    let reply: &[u8] = match serde_json::from_slice::<serde_json::Value>(frame) {
+                        Ok(_) => br#"{"type":"list","data":[]}"#,
+                        Err(_) => br#"{"type":"error","data":"torn call"}"#,
+                    };
+
+                    bus_end.write_all(b"\0").await.unwrap();
+                    bus_end.write_all(reply).await.unwrap();
+                    bus_end.write_all(b"\0").await.unwrap();
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn call_cancelled_while_writing_is_finished_by_the_next() {
+        // The pipe is already full of delimiters, so the first call can't be written until
+        // something reads from the other end.
+        let (mut transport, bus_end) = duplex(16);
+        transport.write_all(&[0; 16]).await.unwrap();
+
+        let bus = AsyncDeviceBus::with_transport(transport);
+        let timeout = Duration::from_millis(50);
+
+        assert!(matches!(
+            bus.call_with_timeout(Call::list(), timeout).await,
+            Err(Error::Timeout)
+        ));
+
+        answer_calls(bus_end);
+
+        assert!(bus.call_with_timeout(Call::list(), timeout).await.is_ok());
+        assert!(bus.call_with_timeout(Call::list(), timeout).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_write_keeps_cancelled_call() {
+        let (mut pipe, bus_end) = duplex(16);
+        pipe.write_all(&[0; 16]).await.unwrap();
+
+        let fail = Arc::new(AtomicBool::new(false));
+        let bus = AsyncDeviceBus::with_transport(Flaky {
+            pipe,
+            fail: Arc::clone(&fail),
+        });
+        let timeout = Duration::from_millis(50);
+
+        assert!(matches!(
+            bus.call_with_timeout(Call::list(), timeout).await,
+            Err(Error::Timeout)
+        ));
+
+        fail.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            bus.call_with_timeout(Call::list(), timeout).await,
+            Err(Error::Io(_))
+        ));
+        fail.store(false, Ordering::SeqCst);
+
+        answer_calls(bus_end);
+
+        // The cancelled call is still written and its response skipped, while the failed call
+        // was never sent.
+        assert!(bus.call_with_timeout(Call::list(), timeout).await.is_ok());
+        assert!(bus.call_with_timeout(Call::list(), timeout).await.is_ok());
+    }
+}
@@ -0,0 +1,124 @@
+//! Exposes the HLAPI console over TCP, for running device code outside of the VM.
+//!
+//! Run this inside the VM, then connect to it from another machine with `DeviceBus::connect_tcp`.
+//! It only listens on the loopback interface unless told otherwise with `--listen`. The secret is
+//! sent in the clear, so the bridge should only be reachable from a trusted network.
+
+use oc2_hlapi::discovery::{DEFAULT_TTY_PATH, SECRET_VAR};
+use oc2_hlapi::error::Result;
+use oc2_hlapi::mux::Multiplexer;
+use oc2_hlapi::transport::Tty;
+use std::env;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: oc2-hlapi-bridge [options]
+
+options:
+    --bus <path>            the HLAPI console to expose (default: /dev/hvc0)
+    --listen <address>      the address to listen on (default: 127.0.0.1:4242), such as
+                            0.0.0.0:4242 to accept connections from other machines
+    --secret <secret>       the secret clients have to present, which can also be set with the
+                            OC2_HLAPI_BRIDGE_SECRET environment variable
+    --cache-list <seconds>  answer `list` calls from a cache for this long
+    --max-message-size <bytes>
+                            the longest call a client may send (default: 4096)
+    -h, --help              print this message";
+
+struct Options {
+    bus: String,
+    listen: String,
+    secret: String,
+    cache_list: Option<Duration>,
+    max_message_size: Option<usize>,
+}
+
+fn parse_args() -> std::result::Result<Options, String> {
+    let mut bus = String::from(DEFAULT_TTY_PATH);
+    let mut listen = String::from("127.0.0.1:4242");
+    let mut secret = env::var(SECRET_VAR).ok();
+    let mut cache_list = None;
+    let mut max_message_size = None;
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {arg}"))
+        };
+
+        match arg.as_str() {
+            "--bus" => bus = value()?,
+            "--listen" => listen = value()?,
+            "--secret" => secret = Some(value()?),
+            "--cache-list" => {
+                let seconds = value()?
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or("--cache-list expects a number of seconds")?;
+
+                cache_list = Some(seconds);
+            }
+            "--max-message-size" => {
+                let size = value()?
+                    .parse::<usize>()
+                    .map_err(|_| "--max-message-size expects a number of bytes")?;
+
+                max_message_size = Some(size);
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ => return Err(format!("unknown argument: {arg}")),
+        }
+    }
+
+    let secret = secret
+        .filter(|secret| !secret.is_empty())
+        .ok_or_else(|| format!("a secret has to be given with --secret or {SECRET_VAR}"))?;
+
+    Ok(Options {
+        bus,
+        listen,
+        secret,
+        cache_list,
+        max_message_size,
+    })
+}
+
+fn run(options: Options) -> Result<()> {
+    let bus = Tty::open(&options.bus)?;
+    let mut multiplexer = Multiplexer::bind_tcp(&options.listen, &options.secret, bus)?;
+
+    if let Some(ttl) = options.cache_list {
+        multiplexer = multiplexer.cache_list(ttl);
+    }
+
+    if let Some(size) = options.max_message_size {
+        multiplexer = multiplexer.max_message_size(size);
+    }
+
+    multiplexer.run()
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("oc2-hlapi-bridge: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Shares the HLAPI console between several processes.
+//!
+//! Run this once inside the VM, then connect to it with `DeviceBus::connect` instead of opening the
+//! console directly.
+
+use oc2_hlapi::discovery::DEFAULT_TTY_PATH;
+use oc2_hlapi::error::Result;
+use oc2_hlapi::mux::{Multiplexer, DEFAULT_SOCKET_PATH};
+use oc2_hlapi::transport::Tty;
+use std::env;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: oc2-hlapi-mux [options]
+
+options:
+    --bus <path>            the HLAPI console to share (default: /dev/hvc0)
+    --socket <path>         the Unix socket to listen on (default: /run/oc2-hlapi.sock)
+    --cache-list <seconds>  answer `list` calls from a cache for this long
+    --max-message-size <bytes>
+                            the longest call a client may send (default: 4096)
+    -h, --help              print this message";
+
+struct Options {
+    bus: String,
+    socket: String,
+    cache_list: Option<Duration>,
+    max_message_size: Option<usize>,
+}
+
+fn parse_args() -> std::result::Result<Options, String> {
+    let mut options = Options {
+        bus: String::from(DEFAULT_TTY_PATH),
+        socket: String::from(DEFAULT_SOCKET_PATH),
+        cache_list: None,
+        max_message_size: None,
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {arg}"))
+        };
+
+        match arg.as_str() {
+            "--bus" => options.bus = value()?,
+            "--socket" => options.socket = value()?,
+            "--cache-list" => {
+                let seconds = value()?
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or("--cache-list expects a number of seconds")?;
+
+                options.cache_list = Some(seconds);
+            }
+            "--max-message-size" => {
+                let size = value()?
+                    .parse::<usize>()
+                    .map_err(|_| "--max-message-size expects a number of bytes")?;
+
+                options.max_message_size = Some(size);
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ => return Err(format!("unknown argument: {arg}")),
+        }
+    }
+
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<()> {
+    let bus = Tty::open(&options.bus)?;
+    let mut multiplexer = Multiplexer::bind(&options.socket, bus)?;
+
+    if let Some(ttl) = options.cache_list {
+        multiplexer = multiplexer.cache_list(ttl);
+    }
+
+    if let Some(size) = options.max_message_size {
+        multiplexer = multiplexer.max_message_size(size);
+    }
+
+    multiplexer.run()
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("oc2-hlapi-mux: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,76 +1,68 @@
 use crate::call::{ApiCall, Call};
 use crate::device::RpcDevice;
-use crate::error::{Error, Result};
-use crate::response::{self, Response};
-use arrayvec::ArrayVec;
+use crate::discovery;
+use crate::error::{Error, MessageKind, Result};
+use crate::middleware::{self, Middleware, Next, Request};
+use crate::mux;
+use crate::rate_limit::RateLimiter;
+use crate::response::{self, Response, ResponseData};
+use crate::retry::{self, RetryPolicy};
+use crate::stats::Stats;
+use crate::trace;
+use crate::transport::{PollSettings, Termios, Transport, Tty};
+use mio::event::Source;
 use mio::unix::SourceFd;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Interest, Registry, Token};
+use serde::de::IgnoredAny;
 use serde::Serialize;
-use std::cell::RefCell;
-use std::fs::{File, OpenOptions};
-use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::any::Any;
+use std::cell::{RefCell, RefMut};
+use std::fmt::{self, Debug};
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
 use std::marker::PhantomData;
-use std::os::unix::io::AsRawFd;
+use std::mem;
+use std::net::ToSocketAddrs;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use termios::Termios;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
-static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(0);
+/// The default maximum size of one message sent or received over a device bus in bytes, not
+/// including its delimiters. This can be changed with [`Builder::max_message_size`].
+pub const MAX_MESSAGE_SIZE: usize = 4096;
 
-/// The maximum size of one message that can be handled by the HLAPI in bytes. 4 KiB by default.
-pub(crate) const MAX_MESSAGE_SIZE: usize = 4096;
+/// A handle to a device bus which HLAPI calls can be made over. Devices are generic over the bus
+/// they were found on, so the same device types work with both [`DeviceBus`] and
+/// [`SyncDeviceBus`].
+pub trait RpcBus: Clone {
+    /// Calls an RPC method, writing the call to the bus and then reading the response.
+    fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> Result<T::Response>;
 
-/// The operating system's device bus. This is represented by a device file in Linux which acts as a
-/// serial console to read and write HLAPI RPC messages.
-#[derive(Clone, Debug)]
-pub struct DeviceBus(Rc<Inner>);
-
-impl DeviceBus {
-    /// Creates a new device bus at the specified path.
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let bus = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(path.as_ref())?;
-
-        let fd = bus.as_raw_fd();
-        let bus_token = Token(NEXT_TOKEN.fetch_add(1, Ordering::Relaxed));
-
-        // Sets options to not echo back the input to the device bus, and immediately applies that
-        // change. Without this, writing to the device bus will just hang the applicaton.
-        // Taken from https://docs.rs/miku-rpc/0.1.4/src/miku_rpc/bus.rs.html#34-37
-        let mut termios = Termios::from_fd(fd)?;
-        termios::cfmakeraw(&mut termios);
-        termios.c_lflag &= !termios::ECHO;
-        termios::tcsetattr(fd, termios::TCSANOW, &termios)?;
-
-        let poll = Poll::new()?;
-        poll.registry()
-            .register(&mut SourceFd(&fd), bus_token, Interest::READABLE)?;
-
-        Ok(Self(Rc::new(Inner {
-            bus,
-            events: RefCell::new(Events::with_capacity(16)),
-            poll: RefCell::new(poll),
-            _not_send_sync: PhantomData,
-        })))
-    }
-
-    /// Calls an RPC method. A convenience method for writing to the device bus and then reading an
-    /// RPC value returned.
-    pub fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> Result<T::Response> {
-        self.write_message(call)?;
-        self.read_message::<T>()
+    /// Calls several RPC methods, returning the result of each call in the same order. Errors which
+    /// only affect one call, such as an error returned by the HLAPI, are returned in place of that
+    /// call's result.
+    ///
+    /// The default implementation makes the calls one at a time. Buses which can send every call
+    /// before waiting for the first response override this.
+    fn call_batch<T, I>(&self, calls: I) -> Result<Vec<Result<T::Response>>>
+    where
+        T: ApiCall + Serialize,
+        I: IntoIterator<Item = Call<T>>,
+    {
+        Ok(calls.into_iter().map(|call| self.call(call)).collect())
     }
 
     /// Finds a device or module by its RpcDevice identifier.
-    pub fn find<D: RpcDevice>(&self) -> Result<Option<D>> {
+    fn find<D: RpcDevice<Bus = Self>>(&self) -> Result<Option<D>> {
         self.find_by_name(D::IDENTIFIER)
     }
 
     /// Finds a device or module by its name.
-    pub fn find_by_name<D: RpcDevice>(&self, name: &str) -> Result<Option<D>> {
+    fn find_by_name<D: RpcDevice<Bus = Self>>(&self, name: &str) -> Result<Option<D>> {
         let response::List(list) = self.call(Call::list())?;
 
         let device = list
@@ -84,90 +76,1840 @@ impl DeviceBus {
 
         Ok(device)
     }
+}
+
+/// Implements the methods which [`DeviceBus`] and [`SyncDeviceBus`] share, for a bus over
+/// `Connection<$transport>` with a `connection` method which locks it.
+macro_rules! shared_methods {
+    ($transport:ty) => {
+        /// Calls an RPC method, waiting for at most the bus' default timeout for the response.
+        pub fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> Result<T::Response> {
+            let policy = self.connection().retry_policy.clone();
+            self.call_retrying(&call, policy.as_deref(), None, |connection, deadline| {
+                connection.call_until(&call, deadline)
+            })
+        }
+
+        /// Calls an RPC method, retrying it according to the given policy instead of the bus' one.
+        pub fn call_with_retry<T: ApiCall + Serialize>(
+            &self,
+            call: Call<T>,
+            policy: &RetryPolicy,
+        ) -> Result<T::Response> {
+            self.call_retrying(&call, Some(policy), None, |connection, deadline| {
+                connection.call_until(&call, deadline)
+            })
+        }
+
+        /// Calls an RPC method, passing the data of its response to `f` while it is still borrowed
+        /// from the bus' read buffer. This lets the response be deserialized into types which
+        /// borrow from it, such as [`ListRef`](response::ListRef), instead of allocating a copy of
+        /// every string in it. `f` is called again if the call is retried.
+        ///
+        /// On a bus with middleware, the data is copied out of the read buffer to pass through the
+        /// middleware, so it is borrowed from that copy instead.
+        pub fn call_with<T, F, O>(&self, call: Call<T>, f: F) -> Result<O>
+        where
+            T: ApiCall + Serialize,
+            F: FnMut(ResponseData<'_>) -> Result<O>,
+        {
+            let policy = self.connection().retry_policy.clone();
+            let mut decoder = Borrowed(f);
+
+            self.call_retrying(&call, policy.as_deref(), None, |connection, deadline| {
+                connection.call_until_with(&call, deadline, &mut decoder)
+            })
+        }
+
+        /// Calls several RPC methods at once, returning the result of each call in the same order.
+        ///
+        /// Every call is written before any response is read, so the HLAPI can answer all of them
+        /// in the same game tick instead of one per tick. Errors which only affect one call, such
+        /// as a call which is too long or an error returned by the HLAPI, are returned in place of
+        /// that call's result. Errors which break the connection, including timeouts, fail the
+        /// whole batch. The bus' default timeout applies to each response separately.
+        ///
+        /// On a bus with middleware, the calls are made one at a time instead, so that each of them
+        /// passes through it.
+        pub fn call_batch<T, I>(&self, calls: I) -> Result<Vec<Result<T::Response>>>
+        where
+            T: ApiCall + Serialize,
+            I: IntoIterator<Item = Call<T>>,
+        {
+            let limiter = self.connection().rate_limiter.clone();
+            let calls = throttle_batch(limiter.as_ref(), calls)?;
+
+            self.connection().call_batch(calls)
+        }
+
+        /// Calls an RPC method, failing with [`Error::Timeout`] if no response was received within
+        /// the given duration. This overrides the bus' default timeout.
+        pub fn call_with_timeout<T: ApiCall + Serialize>(
+            &self,
+            call: Call<T>,
+            timeout: Duration,
+        ) -> Result<T::Response> {
+            self.call_with_deadline(call, Instant::now() + timeout)
+        }
+
+        /// Calls an RPC method, failing with [`Error::Timeout`] if no response was received before
+        /// the given deadline. This overrides the bus' default timeout.
+        pub fn call_with_deadline<T: ApiCall + Serialize>(
+            &self,
+            call: Call<T>,
+            deadline: Instant,
+        ) -> Result<T::Response> {
+            let policy = self.connection().retry_policy.clone();
+            self.call_retrying(
+                &call,
+                policy.as_deref(),
+                Some(deadline),
+                |connection, deadline| connection.call_until(&call, deadline),
+            )
+        }
+
+        /// Makes a call until it succeeds or the policy gives up on it, waiting for the rate
+        /// limiter before each attempt. Without a deadline, the bus' default timeout applies to
+        /// each attempt separately, and starts after the rate limiter.
+        fn call_retrying<T: ApiCall, R>(
+            &self,
+            call: &Call<T>,
+            policy: Option<&RetryPolicy>,
+            deadline: Option<Instant>,
+            mut call_until: impl FnMut(&mut Connection<$transport>, Option<Instant>) -> Result<R>,
+        ) -> Result<R> {
+            let limiter = self.connection().rate_limiter.clone();
+
+            retry::run(policy, call, deadline, || {
+                if let Some(limiter) = &limiter {
+                    limiter.acquire(call.device_id(), deadline)?;
+                }
+
+                let mut attempt = || {
+                    let mut connection = self.connection();
+                    let deadline = deadline.or_else(|| connection.default_deadline());
+
+                    call_until(&mut connection, deadline)
+                };
+
+                match attempt() {
+                    Err(e) if self.connection().reconnect && is_disconnect(&e) => {
+                        match self.reconnect(&e) {
+                            Ok(()) => attempt(),
+                            Err(reopen) if is_unsupported(&reopen) => Err(e),
+                            Err(reopen) => Err(reopen),
+                        }
+                    }
+                    result => result,
+                }
+            })
+        }
+
+        /// Reopens the bus, then runs the reconnect hook with the bus unlocked, so that the hook
+        /// can make calls.
+        fn reconnect(&self, error: &Error) -> Result<()> {
+            self.connection().reconnect(error)?;
+
+            let hook = self.connection().reconnect_hook.take();
+
+            if let Some(mut hook) =
+                hook.and_then(|hook| hook.downcast::<ReconnectHook<Self>>().ok())
+            {
+                hook(self);
+                // The hook may have installed a new hook, which takes its place.
+                self.connection().reconnect_hook.get_or_insert(hook);
+            }
+
+            Ok(())
+        }
+
+        /// Finds a device or module by its RpcDevice identifier.
+        pub fn find<D: RpcDevice<Bus = Self>>(&self) -> Result<Option<D>> {
+            RpcBus::find(self)
+        }
+
+        /// Finds a device or module by its name.
+        pub fn find_by_name<D: RpcDevice<Bus = Self>>(&self, name: &str) -> Result<Option<D>> {
+            RpcBus::find_by_name(self, name)
+        }
+
+        /// Installs a hook which is called with anything received from the bus that isn't a valid
+        /// message, such as kernel messages printed to the console. Those bytes are skipped either
+        /// way.
+        pub fn on_garbage<F: FnMut(&[u8]) + Send + 'static>(&self, hook: F) {
+            self.connection().garbage_hook = Some(Box::new(hook));
+        }
+
+        /// Installs a hook which is called after the bus was reopened, before the interrupted call
+        /// is made again. Devices may have been added, removed or given new IDs, so this is the
+        /// place to find them again. See [`Builder::reconnect`] for details.
+        ///
+        /// The hook is given the bus rather than keeping a clone of it, which would keep the bus
+        /// from ever being dropped.
+        pub fn on_reconnect<F: FnMut(&Self) + Send + 'static>(&self, hook: F) {
+            let hook: ReconnectHook<Self> = Box::new(hook);
+            self.connection().reconnect_hook = Some(Box::new(hook));
+        }
+
+        /// Adds middleware which every call made with [`call`](Self::call) and its variants passes
+        /// through, including the calls made by devices. Middleware added first sees each call
+        /// first. See the [`middleware`](crate::middleware) module for details.
+        pub fn add_middleware<M: Middleware + 'static>(&self, middleware: M) {
+            self.connection().middleware.push(Box::new(middleware));
+        }
+
+        /// Sets the policy which calls made with [`call`](Self::call) and its variants are retried
+        /// under, including the calls made by devices. By default, calls aren't retried. Batches
+        /// made with [`call_batch`](Self::call_batch) aren't retried either way.
+        pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+            self.connection().retry_policy = policy.map(Arc::new);
+        }
+
+        /// Sets the rate limiter which calls made with [`call`](Self::call) and its variants wait
+        /// for, including retries and the calls in a batch. By default, calls aren't limited. See
+        /// the
+        /// [`rate_limit`](crate::rate_limit) module for details.
+        pub fn set_rate_limiter(&self, limiter: Option<RateLimiter>) {
+            self.connection().rate_limiter = limiter;
+        }
+
+        /// Sets the collector which counts the calls made with [`call`](Self::call) and its
+        /// variants, including the calls made by devices. By default, no statistics are collected.
+        /// See the
+        /// [`stats`](crate::stats) module for details.
+        pub fn set_stats(&self, stats: Option<Stats>) {
+            self.connection().stats = stats;
+        }
+
+        /// Changes the terminal attributes of the console the bus is open on with the given
+        /// function, and applies them immediately. The console starts out in raw mode, without
+        /// echo. Fails with
+        /// [`Error::Io`] if the bus isn't open on a terminal.
+        pub fn configure<F: FnMut(&mut Termios)>(&self, mut f: F) -> Result<()> {
+            self.connection().transport.configure(&mut f)?;
+            Ok(())
+        }
+
+        /// Changes how the bus' transport waits for the console to become ready with the given
+        /// function, such as to stop writes from waiting forever on a console which stopped
+        /// reading. Fails with [`Error::Io`] if the transport has no such settings.
+        pub fn configure_poll<F: FnMut(&mut PollSettings)>(&self, mut f: F) -> Result<()> {
+            self.connection().transport.configure_poll(&mut f)?;
+            Ok(())
+        }
+
+        /// Closes the transport of the bus, reporting any error that dropping the bus would have
+        /// ignored, such as failing to restore the console's terminal attributes. Every call made
+        /// on the bus after this, including by devices found on it, fails with [`Error::Io`].
+        pub fn close(&self) -> Result<()> {
+            self.connection().close(Box::new(Closed))
+        }
+
+        /// Throws away everything which has been received but not read yet, and anything else which
+        /// arrives until the bus has been quiet for a moment. Returns the number of bytes thrown
+        /// away.
+        ///
+        /// This recovers a bus whose responses no longer line up with its calls, for example after
+        /// a response was cut off by output from another program. Calls which timed out are
+        /// forgotten, so their responses must have arrived by the time this returns.
+        pub fn resync(&self) -> Result<usize> {
+            self.connection().resync()
+        }
+    };
+}
+
+/// The operating system's device bus. This is usually represented by a device file in Linux which
+/// acts as a serial console to read and write HLAPI RPC messages, but any [`Transport`] can be
+/// used to carry the messages.
+///
+/// A `DeviceBus` can only be used from the thread it was created on. See [`SyncDeviceBus`] for a
+/// bus which can be shared between threads.
+#[derive(Clone, Debug)]
+pub struct DeviceBus(Rc<RefCell<Connection>>);
+
+impl DeviceBus {
+    /// Creates a new device bus at the specified path.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Builder::new().open(path)
+    }
+
+    /// Creates a new device bus which sends and receives messages over the given transport.
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
+        Builder::new().build(transport)
+    }
+
+    /// Opens the device bus set by the `OC2_HLAPI_BUS` environment variable or the config file, or
+    /// otherwise the default one. See the [`discovery`](crate::discovery) module for details.
+    pub fn open_default() -> Result<Self> {
+        Builder::new().open_default()
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) listening on the Unix socket at the
+    /// specified path, usually [`DEFAULT_SOCKET_PATH`](crate::mux::DEFAULT_SOCKET_PATH).
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Builder::new().connect(path)
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) serving a bus over TCP at the
+    /// specified address, presenting the given secret to it.
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A, secret: &str) -> Result<Self> {
+        Builder::new().connect_tcp(addr, secret)
+    }
+
+    /// Creates a builder for configuring a new device bus.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    shared_methods!(dyn Transport);
 
     /// Writes an RPC message.
     pub fn write_message<T: ApiCall + Serialize>(&self, message: Call<T>) -> Result<()> {
-        let mut write_buffer = const { ArrayVec::<_, MAX_MESSAGE_SIZE>::new_const() };
+        self.connection().write_message(&message).map(drop)
+    }
+
+    /// Writes an RPC message without waiting for its response, which can be read later with
+    /// [`try_read_message`](Self::try_read_message) or [`read_message`](Self::read_message).
+    ///
+    /// In non-blocking mode, the part of the message which couldn't be written right away is kept
+    /// and written by later calls to `send` or [`try_flush`](Self::try_flush). Returns whether the
+    /// whole message was written.
+    pub fn send<T: ApiCall + Serialize>(&self, message: Call<T>) -> Result<bool> {
+        self.connection().write_message(&message)
+    }
+
+    /// Writes as much as possible of the messages which [`send`](Self::send) couldn't write right
+    /// away, returning whether all of them have been written. Call this once the bus becomes
+    /// writable again.
+    pub fn try_flush(&self) -> Result<bool> {
+        self.connection().flush_writes()
+    }
+
+    /// Reads an RPC message if a whole one can be read without blocking. This is meant to be used
+    /// in non-blocking mode, once the bus becomes readable. Since a single read can receive several
+    /// responses, this should be called until it returns `None` before waiting for the bus to
+    /// become readable again.
+    pub fn try_read_message<T: ApiCall>(&self) -> Result<Option<T::Response>> {
+        self.connection().try_read_message::<T>()
+    }
+
+    /// Switches the bus into or out of non-blocking mode. In non-blocking mode, reads and writes
+    /// never wait for the transport, which lets the bus be driven by an external event loop through
+    /// its [`Source`] implementation. Blocking reads such as [`call`](Self::call) fail with
+    /// [`Error::Timeout`] when no response is ready while the bus is in non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.connection().set_nonblocking(nonblocking)
+    }
 
-        write_buffer
-            .try_push(b'\0')
-            .map_err(|_| Error::MessageLengthExceeded)?;
+    fn raw_fd(&self) -> io::Result<RawFd> {
+        self.connection().transport.raw_fd().ok_or_else(|| {
+            io::Error::new(
+                IoErrorKind::Unsupported,
+                "the bus' transport has no file descriptor",
+            )
+        })
+    }
+
+    /// Reads an RPC message, waiting for at most the bus' default timeout.
+    pub fn read_message<T: ApiCall>(&self) -> Result<T::Response> {
+        let mut connection = self.connection();
+        let deadline = connection.default_deadline();
+
+        connection.read_message_until::<T>(deadline)
+    }
 
-        serde_json::to_writer(&mut write_buffer, &message).map_err(Error::from)?;
+    /// Reads an RPC message, failing with [`Error::Timeout`] if none was received before the given
+    /// deadline. A response which arrives after its call timed out is discarded, rather than being
+    /// returned from the next read.
+    pub fn read_message_until<T: ApiCall>(&self, deadline: Option<Instant>) -> Result<T::Response> {
+        self.connection().read_message_until::<T>(deadline)
+    }
 
-        write_buffer
-            .try_push(b'\0')
-            .map_err(|_| Error::MessageLengthExceeded)?;
+    fn connection(&self) -> RefMut<'_, Connection> {
+        self.0.borrow_mut()
+    }
+}
 
-        (&self.0.bus)
-            .write_all(write_buffer.as_slice())
-            .map_err(Error::from)?;
+impl RpcBus for DeviceBus {
+    fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> Result<T::Response> {
+        DeviceBus::call(self, call)
+    }
 
-        (&self.0.bus).flush()?;
+    fn call_batch<T, I>(&self, calls: I) -> Result<Vec<Result<T::Response>>>
+    where
+        T: ApiCall + Serialize,
+        I: IntoIterator<Item = Call<T>>,
+    {
+        DeviceBus::call_batch(self, calls)
+    }
+}
 
+/// Registers the bus' transport with an external `mio` event loop. This fails with
+/// [`IoErrorKind::Unsupported`] if the transport isn't backed by a file descriptor.
+///
+/// When the bus [reconnects](Builder::reconnect), the new transport is registered in place of the
+/// old one with the same registry, token and interests, so the event loop doesn't have to do
+/// anything about it.
+impl Source for DeviceBus {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.raw_fd()?).register(registry, token, interests)?;
+        self.connection().registration = Some(Registration::new(registry, token, interests)?);
         Ok(())
     }
 
-    /// Reads an RPC message.
-    pub fn read_message<T: ApiCall>(&self) -> Result<T::Response> {
-        let mut read_buffer = const { [0u8; MAX_MESSAGE_SIZE] };
-        let mut total_bytes = 0;
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.raw_fd()?).reregister(registry, token, interests)?;
+        self.connection().registration = Some(Registration::new(registry, token, interests)?);
+        Ok(())
+    }
 
-        loop {
-            let bytes_read = self.read(&mut read_buffer[total_bytes..])?;
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.raw_fd()?).deregister(registry)?;
+        self.connection().registration = None;
+        Ok(())
+    }
+}
 
-            if bytes_read > 0 {
-                total_bytes += bytes_read;
+/// Where a bus' transport is registered with an external `mio` event loop.
+struct Registration {
+    registry: Registry,
+    token: Token,
+    interests: Interest,
+}
 
-                if bytes_read > 1 && read_buffer[total_bytes - 1] == b'\0' {
-                    break;
-                }
-            } else {
-                return Err(Error::ReadZero);
-            }
+impl Registration {
+    fn new(registry: &Registry, token: Token, interests: Interest) -> io::Result<Self> {
+        Ok(Self {
+            registry: registry.try_clone()?,
+            token,
+            interests,
+        })
+    }
+}
+
+/// A device bus which can be shared between threads.
+///
+/// Each call holds a lock on the bus from the moment it is written until its response has been
+/// read, so calls made from several threads at once never have their messages interleaved. Time
+/// spent waiting for other threads to finish their calls counts towards a call's timeout. The bus
+/// is unlocked while a call waits for the rate limiter or before being retried, but a batch made
+/// on a bus with middleware holds the lock until all of its calls are done. Devices found on a
+/// `SyncDeviceBus`, such as `RobotDevice<SyncDeviceBus>`, can be sent to other threads as well.
+#[derive(Clone, Debug)]
+pub struct SyncDeviceBus(Arc<Mutex<Connection<dyn Transport + Send>>>);
+
+impl SyncDeviceBus {
+    /// Creates a new shared device bus at the specified path.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Builder::new().open_sync(path)
+    }
+
+    /// Creates a new shared device bus which sends and receives messages over the given transport.
+    pub fn with_transport<T: Transport + Send + 'static>(transport: T) -> Self {
+        Builder::new().build_sync(transport)
+    }
+
+    /// Opens the device bus set by the `OC2_HLAPI_BUS` environment variable or the config file, or
+    /// otherwise the default one. See the [`discovery`](crate::discovery) module for details.
+    pub fn open_default() -> Result<Self> {
+        Builder::new().open_default_sync()
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) listening on the Unix socket at the
+    /// specified path, usually [`DEFAULT_SOCKET_PATH`](crate::mux::DEFAULT_SOCKET_PATH).
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Builder::new().connect_sync(path)
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) serving a bus over TCP at the
+    /// specified address, presenting the given secret to it.
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A, secret: &str) -> Result<Self> {
+        Builder::new().connect_tcp_sync(addr, secret)
+    }
+
+    shared_methods!(dyn Transport + Send);
+
+    fn connection(&self) -> MutexGuard<'_, Connection<dyn Transport + Send>> {
+        // A panic while the lock was held can only have happened between whole reads and writes,
+        // and the timeout bookkeeping keeps responses paired up with their calls after that.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl RpcBus for SyncDeviceBus {
+    fn call<T: ApiCall + Serialize>(&self, call: Call<T>) -> Result<T::Response> {
+        SyncDeviceBus::call(self, call)
+    }
+
+    fn call_batch<T, I>(&self, calls: I) -> Result<Vec<Result<T::Response>>>
+    where
+        T: ApiCall + Serialize,
+        I: IntoIterator<Item = Call<T>>,
+    {
+        SyncDeviceBus::call_batch(self, calls)
+    }
+}
+
+/// A builder for a [`DeviceBus`] or [`SyncDeviceBus`] with non-default settings.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Builder {
+    pub(crate) max_message_size: usize,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) reconnect: bool,
+}
+
+impl Builder {
+    /// Creates a builder with the default settings.
+    pub fn new() -> Self {
+        Self {
+            max_message_size: MAX_MESSAGE_SIZE,
+            timeout: None,
+            reconnect: false,
         }
+    }
 
-        // The message without the null bytes at the start and end.
-        let msg_slice = &read_buffer[1..total_bytes - 1];
+    /// Sets the maximum size in bytes of a single message sent or received by the bus. Larger
+    /// messages fail with [`Error::MessageLengthExceeded`]. Defaults to [`MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
 
-        serde_json::from_slice::<Response<T>>(msg_slice)
-            .map_err(Error::from)?
-            .into()
+    /// Sets how long a call waits for its response before failing with [`Error::Timeout`]. By
+    /// default, calls wait forever.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
     }
 
-    fn read(&self, buf: &mut [u8]) -> Result<usize> {
-        {
-            let mut poll = self.0.poll.borrow_mut();
-            let mut events = self.0.events.borrow_mut();
-            events.clear();
+    /// Sets whether the bus reopens its transport when it stops working, such as when the console
+    /// returns `EIO` after the computer's devices were reset. The call which was interrupted is
+    /// then made once more, after the hook set with [`DeviceBus::on_reconnect`] has run. Only calls
+    /// made with `call` and its variants reconnect. Disabled by default, and only works for buses
+    /// opened on a path.
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
 
-            while let Err(e) = poll.poll(&mut events, None) {
-                if e.kind() != IoErrorKind::Interrupted {
-                    return Err(e.into());
-                }
-            }
+    /// Creates a new device bus at the specified path.
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Result<DeviceBus> {
+        Ok(self.build(Tty::open(path)?))
+    }
+
+    /// Opens the device bus set by the `OC2_HLAPI_BUS` environment variable or the config file, or
+    /// otherwise the default one. See the [`discovery`](crate::discovery) module for details.
+    pub fn open_default(self) -> Result<DeviceBus> {
+        let transport = discovery::open_default(self.timeout)?;
+        Ok(self.build(transport))
+    }
+
+    /// Creates a new device bus which sends and receives messages over the given transport.
+    pub fn build<T: Transport + 'static>(self, transport: T) -> DeviceBus {
+        DeviceBus(Rc::new(RefCell::new(self.connection(Box::new(transport)))))
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) listening on the Unix socket at the
+    /// specified path.
+    pub fn connect<P: AsRef<Path>>(self, path: P) -> Result<DeviceBus> {
+        Ok(self.build(UnixStream::connect(path)?))
+    }
+
+    /// Connects to a [`Multiplexer`](crate::mux::Multiplexer) serving a bus over TCP at the
+    /// specified address, presenting the given secret to it. The bus' timeout also applies to the
+    /// handshake.
+    pub fn connect_tcp<A: ToSocketAddrs>(self, addr: A, secret: &str) -> Result<DeviceBus> {
+        let stream = mux::connect_tcp(addr, secret, self.timeout)?;
+        Ok(self.build(stream))
+    }
+
+    /// Creates a new shared device bus at the specified path.
+    pub fn open_sync<P: AsRef<Path>>(self, path: P) -> Result<SyncDeviceBus> {
+        Ok(self.build_sync(Tty::open(path)?))
+    }
+
+    /// Opens a new shared device bus as described in [`open_default`](Self::open_default).
+    pub fn open_default_sync(self) -> Result<SyncDeviceBus> {
+        let transport = discovery::open_default(self.timeout)?;
+        Ok(self.build_sync(transport))
+    }
+
+    /// Creates a new shared device bus which sends and receives messages over the given transport.
+    pub fn build_sync<T: Transport + Send + 'static>(self, transport: T) -> SyncDeviceBus {
+        SyncDeviceBus(Arc::new(Mutex::new(self.connection(Box::new(transport)))))
+    }
+
+    /// Connects a new shared device bus to a [`Multiplexer`](crate::mux::Multiplexer) listening on
+    /// the Unix socket at the specified path.
+    pub fn connect_sync<P: AsRef<Path>>(self, path: P) -> Result<SyncDeviceBus> {
+        Ok(self.build_sync(UnixStream::connect(path)?))
+    }
+
+    /// Connects a new shared device bus to a [`Multiplexer`](crate::mux::Multiplexer) serving a bus
+    /// over TCP at the specified address, presenting the given secret to it. The bus' timeout also
+    /// applies to the handshake.
+    pub fn connect_tcp_sync<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        secret: &str,
+    ) -> Result<SyncDeviceBus> {
+        let stream = mux::connect_tcp(addr, secret, self.timeout)?;
+        Ok(self.build_sync(stream))
+    }
+
+    fn connection<T: ?Sized>(self, transport: Box<T>) -> Connection<T> {
+        Connection {
+            transport,
+            decoder: FrameDecoder::with_max_len(self.max_message_size),
+            write_buffer: Vec::new(),
+            max_message_size: self.max_message_size,
+            timeout: self.timeout,
+            nonblocking: false,
+            stale_responses: 0,
+            garbage_skipped: false,
+            needs_resync: false,
+            garbage_hook: None,
+            middleware: Vec::new(),
+            retry_policy: None,
+            rate_limiter: None,
+            stats: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            reconnect: self.reconnect,
+            reconnect_hook: None,
+            registration: None,
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A decoder which splits a stream of bytes into HLAPI messages.
+///
+/// Each message is surrounded by a `\0` byte on either side. The decoder keeps any bytes which
+/// haven't formed a full message yet between calls, so it doesn't matter how the stream is split up
+/// when it is read, or how many messages arrive at once.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // The start of the bytes in the buffer which haven't been decoded yet.
+    position: usize,
+    max_len: usize,
+    // A message longer than `max_len` which is being thrown away as it arrives.
+    discarded: Option<Discarded>,
+}
+
+/// A segment of the stream which was too long to be a message.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct Discarded {
+    // The number of bytes in the segment so far.
+    length: usize,
+    // The first `max_len` bytes of the segment, which tell a message that was too long apart from
+    // garbage.
+    head: Vec<u8>,
+}
+
+/// A segment of the stream between delimiters.
+enum Segment {
+    Message { start: usize, len: usize },
+    TooLong(Discarded),
+}
+
+impl FrameDecoder {
+    /// The number of bytes reserved in the buffer before each read.
+    pub(crate) const READ_SIZE: usize = MAX_MESSAGE_SIZE;
+
+    /// Creates a new, empty decoder which accepts messages of any length.
+    pub fn new() -> Self {
+        Self::with_max_len(usize::MAX)
+    }
+
+    /// Creates a new, empty decoder which rejects messages longer than `max_len` bytes. Rejected
+    /// messages are thrown away as they are received instead of being buffered.
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            position: 0,
+            max_len,
+            discarded: None,
+        }
+    }
+
+    /// Appends bytes received from the stream to the decoder.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reads once from the given reader, appending the bytes read to the decoder. Returns the
+    /// number of bytes read.
+    pub fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<usize> {
+        self.compact();
+
+        let len = self.buffer.len();
+        self.buffer.resize(len + Self::READ_SIZE, 0);
+
+        let result = reader.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *result.as_ref().unwrap_or(&0));
+
+        result.map_err(Error::from)
+    }
+
+    /// Returns the next complete message without its delimiters, or `None` if no full message has
+    /// been received yet. Once the end of a message which was too long has been received, this
+    /// returns [`Error::MessageLengthExceeded`] and decoding can continue with the next message.
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>> {
+        match self.next_segment() {
+            None => Ok(None),
+            Some(Segment::Message { start, len }) => Ok(Some(&self.buffer[start..start + len])),
+            Some(Segment::TooLong(discarded)) => Err(self.length_exceeded(discarded.length)),
         }
+    }
+
+    /// Returns the next complete message from a decoder created with [`new`](Self::new), which
+    /// can't fail since it accepts messages of any length.
+    pub(crate) fn next_unbounded_frame(&mut self) -> Option<&[u8]> {
+        debug_assert_eq!(self.max_len, usize::MAX, "decoder has a length limit");
+
+        self.next_frame()
+            .expect("unbounded decoder rejected a message")
+    }
 
+    /// Returns the next complete message which is a JSON object, like
+    /// [`next_frame`](Self::next_frame).
+    ///
+    /// Anything else between the delimiters, such as kernel messages printed to the console or
+    /// the start of a message which was cut off, is skipped and passed to `on_garbage`. So is
+    /// anything in front of the opening brace of a valid message.
+    ///
+    /// A segment which starts with an opening brace but isn't valid JSON is a message which was
+    /// damaged on the way, rather than garbage. It is returned as [`Error::Json`], so that it still
+    /// answers the call it was meant for. The same goes for [`Error::MessageLengthExceeded`]: only
+    /// a segment which starts like a message is reported as too long, while anything else is
+    /// garbage however long it is, and only its start is passed to `on_garbage`.
+    pub fn next_valid_frame<F>(&mut self, mut on_garbage: F) -> Result<Option<&[u8]>>
+    where
+        F: FnMut(&[u8]),
+    {
         loop {
-            let result = (&self.0.bus).read(buf);
+            let (start, len) = match self.next_segment() {
+                None => return Ok(None),
+                Some(Segment::Message { start, len }) => (start, len),
+                Some(Segment::TooLong(discarded)) if discarded.head.starts_with(b"{") => {
+                    return Err(self.length_exceeded(discarded.length));
+                }
+                Some(Segment::TooLong(discarded)) => {
+                    on_garbage(&discarded.head);
+                    continue;
+                }
+            };
 
-            match result {
-                Ok(n) => break Ok(n),
-                Err(e) if e.kind() != IoErrorKind::Interrupted => {
-                    break Err(e.into());
+            let segment = &self.buffer[start..start + len];
+
+            let Some(offset) = segment
+                .iter()
+                .enumerate()
+                .filter(|&(_, &b)| b == b'{')
+                .map(|(i, _)| i)
+                .find(|&i| serde_json::from_slice::<IgnoredAny>(&segment[i..]).is_ok())
+            else {
+                if segment.starts_with(b"{") {
+                    return Err(serde_json::from_slice::<IgnoredAny>(segment)
+                        .expect_err("segment failed to parse before")
+                        .into());
                 }
-                _ => continue,
+
+                on_garbage(segment);
+                continue;
+            };
+
+            if offset > 0 {
+                on_garbage(&segment[..offset]);
+            }
+
+            return Ok(Some(&self.buffer[start + offset..start + len]));
+        }
+    }
+
+    /// Finds the next complete segment in the buffer.
+    fn next_segment(&mut self) -> Option<Segment> {
+        let pending = &self.buffer[self.position..];
+
+        if let Some(discarded) = &mut self.discarded {
+            let Some(len) = pending.iter().position(|&b| b == b'\0') else {
+                discarded.length += pending.len();
+                self.position = self.buffer.len();
+                return None;
+            };
+
+            discarded.length += len;
+            self.position += len + 1;
+
+            return self.discarded.take().map(Segment::TooLong);
+        }
+
+        // The closing delimiter of one message and the opening delimiter of the next are right next
+        // to each other, so any number of delimiters in a row are skipped.
+        let Some(start) = pending.iter().position(|&b| b != b'\0') else {
+            self.position = self.buffer.len();
+            return None;
+        };
+
+        let Some(len) = pending[start..].iter().position(|&b| b == b'\0') else {
+            let len = pending.len() - start;
+
+            if len > self.max_len {
+                self.discarded = Some(Discarded {
+                    length: len,
+                    head: pending[start..start + self.max_len].to_vec(),
+                });
+                self.position = self.buffer.len();
             }
+
+            return None;
+        };
+
+        let start = self.position + start;
+        self.position = start + len + 1;
+
+        if len > self.max_len {
+            return Some(Segment::TooLong(Discarded {
+                length: len,
+                head: self.buffer[start..start + self.max_len].to_vec(),
+            }));
+        }
+
+        Some(Segment::Message { start, len })
+    }
+
+    /// Returns the bytes which have been received but haven't been returned as a message yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer[self.position..]
+    }
+
+    /// Returns whether the message being received is already longer than the limit, so that it
+    /// is thrown away as it arrives.
+    pub(crate) fn is_discarding(&self) -> bool {
+        self.discarded.is_some()
+    }
+
+    /// Discards every byte which hasn't been returned as a message yet.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.position = 0;
+        self.discarded = None;
+    }
+
+    fn length_exceeded(&self, length: usize) -> Error {
+        Error::MessageLengthExceeded {
+            kind: MessageKind::Response,
+            length,
+            max: self.max_len,
+        }
+    }
+
+    fn compact(&mut self) {
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
         }
     }
 }
 
-#[derive(Debug)]
-struct Inner {
-    bus: File,
-    poll: RefCell<Poll>,
-    events: RefCell<Events>,
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of one connection to the HLAPI, shared by every handle to a bus.
+struct Connection<T: ?Sized = dyn Transport> {
+    transport: Box<T>,
+    decoder: FrameDecoder,
+    // Calls which haven't been written yet. This is only ever non-empty after a write would have
+    // blocked while the connection is in non-blocking mode.
+    write_buffer: Vec<u8>,
+    max_message_size: usize,
+    timeout: Option<Duration>,
+    nonblocking: bool,
+    // The number of calls which timed out before their response was received.
+    stale_responses: usize,
+    // Whether garbage was skipped while waiting for the current response.
+    garbage_skipped: bool,
+    // Whether the stale responses can't be counted on anymore, so that the bus has to be resynced
+    // before the next call.
+    needs_resync: bool,
+    garbage_hook: Option<GarbageHook>,
+    middleware: Vec<Box<dyn Middleware>>,
+    retry_policy: Option<Arc<RetryPolicy>>,
+    rate_limiter: Option<RateLimiter>,
+    stats: Option<Stats>,
+    // The number of bytes written and read for calls so far, which the statistics are taken from.
+    bytes_sent: u64,
+    bytes_received: u64,
+    reconnect: bool,
+    // A `ReconnectHook` for the type of bus the connection belongs to.
+    reconnect_hook: Option<Box<dyn Any + Send>>,
+    // Where the transport has to be registered again after reconnecting.
+    registration: Option<Registration>,
+}
+
+type GarbageHook = Box<dyn FnMut(&[u8]) + Send>;
+type ReconnectHook<B> = Box<dyn FnMut(&B) + Send>;
+
+/// How long the bus has to stay quiet before a resync is finished. The HLAPI answers calls once per
+/// game tick, so this leaves time for two ticks.
+const RESYNC_QUIET_TIME: Duration = Duration::from_millis(100);
+
+impl<T: Transport + ?Sized> Connection<T> {
+    fn call_until<C: ApiCall + Serialize>(
+        &mut self,
+        call: &Call<C>,
+        deadline: Option<Instant>,
+    ) -> Result<C::Response> {
+        let result = self.call_until_with(call, deadline, &mut Owned::<C>(PhantomData));
+
+        self.learn_devices(&result);
+        result
+    }
 
-    // Ensures that this struct is not Send or Sync
-    _not_send_sync: PhantomData<*mut ()>,
+    fn call_until_with<C: ApiCall + Serialize, D: Decode>(
+        &mut self,
+        call: &Call<C>,
+        deadline: Option<Instant>,
+        decoder: &mut D,
+    ) -> Result<D::Output> {
+        trace::call(call, || {
+            let start = Instant::now();
+            let (sent, received) = (self.bytes_sent, self.bytes_received);
+            let result = self.exchange(call, deadline, decoder);
+
+            self.record(
+                call,
+                &result,
+                start,
+                self.bytes_sent - sent,
+                self.bytes_received - received,
+            );
+            result
+        })
+    }
+
+    fn exchange<C: ApiCall + Serialize, D: Decode>(
+        &mut self,
+        call: &Call<C>,
+        deadline: Option<Instant>,
+        decoder: &mut D,
+    ) -> Result<D::Output> {
+        if self.middleware.is_empty() {
+            self.write_message(call)?;
+            return self.read_until(deadline, |message| decoder.decode(message));
+        }
+
+        let data = self.call_through_middleware(&Request::new(call)?, deadline)?;
+        decoder.decode_data(data)
+    }
+
+    /// Adds a finished call to the statistics, if they are being collected.
+    fn record<C: ApiCall, R>(
+        &self,
+        call: &Call<C>,
+        result: &Result<R>,
+        start: Instant,
+        sent: u64,
+        received: u64,
+    ) {
+        if let Some(stats) = &self.stats {
+            stats.record(call, result.is_err(), start.elapsed(), sent, received);
+        }
+    }
+
+    /// Passes the devices in the response to a `list` call on to the statistics, which use them to
+    /// tell the types of devices apart.
+    fn learn_devices<R: 'static>(&self, result: &Result<R>) {
+        if let (Some(stats), Ok(response)) = (&self.stats, result) {
+            if let Some(list) = (response as &dyn Any).downcast_ref() {
+                stats.learn_devices(list);
+            }
+        }
+    }
+
+    fn call_through_middleware(
+        &mut self,
+        request: &Request<'_>,
+        deadline: Option<Instant>,
+    ) -> Result<Value> {
+        // The chain is taken out of the connection while it runs, so that the end of it can use
+        // the connection to make the call.
+        let mut chain = mem::take(&mut self.middleware);
+
+        let result = Next::new(&mut chain, &mut |request: &Request<'_>| {
+            self.write_message(&request.message)?;
+            self.read_until(deadline, middleware::decode_raw)
+        })
+        .run(request);
+
+        self.middleware = chain;
+        result
+    }
+
+    fn call_batch<C, I>(&mut self, calls: I) -> Result<Vec<Result<C::Response>>>
+    where
+        C: ApiCall + Serialize,
+        I: IntoIterator<Item = Call<C>>,
+    {
+        if !self.middleware.is_empty() {
+            return Ok(calls
+                .into_iter()
+                .map(|call| {
+                    let deadline = self.default_deadline();
+                    self.call_until(&call, deadline)
+                })
+                .collect());
+        }
+
+        let calls = calls.into_iter().collect::<Vec<_>>();
+        let start = Instant::now();
+
+        // Calls which fail to encode are never sent, so they don't get a response either.
+        let encoded = calls
+            .iter()
+            .map(|call| {
+                let sent = self.bytes_sent;
+                self.encode(call).map(|()| self.bytes_sent - sent)
+            })
+            .collect::<Vec<_>>();
+
+        self.flush_writes()?;
+
+        let mut unanswered = encoded.iter().filter(|encoded| encoded.is_ok()).count();
+        let mut results = Vec::with_capacity(encoded.len());
+
+        for (call, encoded) in calls.iter().zip(encoded) {
+            let sent = match encoded {
+                Ok(sent) => sent,
+                Err(e) => {
+                    let result = Err(e);
+
+                    self.record(call, &result, start, 0, 0);
+                    results.push(result);
+                    continue;
+                }
+            };
+
+            unanswered -= 1;
+
+            let deadline = self.default_deadline();
+            let received = self.bytes_received;
+            let result = self.read_message_until::<C>(deadline);
+
+            self.record(call, &result, start, sent, self.bytes_received - received);
+            self.learn_devices(&result);
+
+            match result {
+                Err(e @ (Error::Timeout | Error::ReadZero | Error::Io(_))) => {
+                    // The rest of the batch will still be answered, and those responses have to be
+                    // skipped like the response to a call which timed out.
+                    self.stale_responses += unanswered;
+                    return Err(e);
+                }
+                result => results.push(result),
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn default_deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    fn write_message<M: Serialize + ?Sized>(&mut self, message: &M) -> Result<bool> {
+        self.encode(message)?;
+        self.flush_writes()
+    }
+
+    /// Adds a call to the calls waiting to be written.
+    fn encode<M: Serialize + ?Sized>(&mut self, message: &M) -> Result<()> {
+        if self.needs_resync && self.write_buffer.is_empty() {
+            self.resync()?;
+        }
+
+        let pending = self.write_buffer.len();
+
+        encode_message(&mut self.write_buffer, message, self.max_message_size)?;
+
+        trace::message_sent(&self.write_buffer[pending..]);
+        self.bytes_sent += (self.write_buffer.len() - pending) as u64;
+
+        Ok(())
+    }
+
+    /// Writes as many of the buffered calls as possible, returning whether all of them were
+    /// written. This can only return `false` in non-blocking mode.
+    fn flush_writes(&mut self) -> Result<bool> {
+        while !self.write_buffer.is_empty() {
+            match self.transport.write(&self.write_buffer) {
+                Ok(0) => {
+                    self.write_buffer.clear();
+                    return Err(Error::Io(IoErrorKind::WriteZero.into()));
+                }
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                }
+                Err(e) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(e) if e.kind() == IoErrorKind::WouldBlock && self.nonblocking => {
+                    return Ok(false);
+                }
+                Err(e) => {
+                    // Whatever was left of the message can't be recovered, so it is dropped rather
+                    // than sent as the start of the next message.
+                    self.write_buffer.clear();
+                    return Err(e.into());
+                }
+            }
+        }
+
+        self.transport.flush()?;
+
+        Ok(true)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<()> {
+        self.transport.set_nonblocking(nonblocking)?;
+        self.nonblocking = nonblocking;
+
+        Ok(())
+    }
+
+    /// Reopens the transport after it failed with the given error, forgetting everything that was
+    /// in flight on the old one.
+    fn reconnect(&mut self, error: &Error) -> Result<()> {
+        trace::reconnect(error);
+        self.transport.reopen()?;
+
+        // The old file descriptor left the event loop when it was closed.
+        if let (Some(registration), Some(fd)) = (&self.registration, self.transport.raw_fd()) {
+            SourceFd(&fd).register(
+                &registration.registry,
+                registration.token,
+                registration.interests,
+            )?;
+        }
+
+        self.decoder = FrameDecoder::with_max_len(self.max_message_size);
+        self.write_buffer.clear();
+        self.stale_responses = 0;
+
+        Ok(())
+    }
+
+    /// Replaces the transport with a closed one, then closes the old one.
+    fn close(&mut self, closed: Box<T>) -> Result<()> {
+        let transport = mem::replace(&mut self.transport, closed);
+        T::close(transport)?;
+
+        Ok(())
+    }
+
+    fn resync(&mut self) -> Result<usize> {
+        let mut discarded = self.decoder.pending().len();
+
+        self.decoder.clear();
+        self.stale_responses = 0;
+        self.needs_resync = false;
+        self.transport.set_read_timeout(Some(RESYNC_QUIET_TIME))?;
+
+        loop {
+            match self.decoder.read_from(&mut *self.transport) {
+                Ok(0) => break,
+                Ok(n) => {
+                    discarded += n;
+                    self.decoder.clear();
+                }
+                Err(Error::Io(e))
+                    if matches!(e.kind(), IoErrorKind::TimedOut | IoErrorKind::WouldBlock) =>
+                {
+                    break;
+                }
+                Err(Error::Io(e)) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.transport.set_read_timeout(None)?;
+
+        Ok(discarded)
+    }
+
+    /// Decodes the next response which has already been received, if there is one.
+    fn next_response<R>(
+        &mut self,
+        decode: &mut impl FnMut(&[u8]) -> Result<R>,
+    ) -> Option<Result<R>> {
+        loop {
+            let hook = &mut self.garbage_hook;
+            let garbage_skipped = &mut self.garbage_skipped;
+
+            let frame = self.decoder.next_valid_frame(|garbage| {
+                trace::garbage(garbage);
+                *garbage_skipped = true;
+
+                if let Some(hook) = hook {
+                    hook(garbage);
+                }
+            });
+
+            if let Ok(Some(frame)) = frame {
+                trace::message_received(frame);
+                // The frame doesn't include its delimiters.
+                self.bytes_received += frame.len() as u64 + 2;
+            }
+
+            match frame {
+                Ok(None) => return None,
+                // Responses arrive in the same order that calls were made, so the next ones are
+                // meant for calls which were given up on already.
+                _ if self.stale_responses > 0 => self.stale_responses -= 1,
+                Ok(Some(frame)) => return Some(decode(frame)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn try_read_message<C: ApiCall>(&mut self) -> Result<Option<C::Response>> {
+        loop {
+            if let Some(response) = self.next_response(&mut decode_response::<C>) {
+                return response.map(Some);
+            }
+
+            match self.decoder.read_from(&mut *self.transport) {
+                Ok(0) => return Err(Error::ReadZero),
+                Ok(_) => {}
+                Err(Error::Io(e)) if e.kind() == IoErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_message_until<C: ApiCall>(&mut self, deadline: Option<Instant>) -> Result<C::Response> {
+        self.read_until(deadline, decode_response::<C>)
+    }
+
+    fn read_until<R>(
+        &mut self,
+        deadline: Option<Instant>,
+        mut decode: impl FnMut(&[u8]) -> Result<R>,
+    ) -> Result<R> {
+        let stale = self.stale_responses > 0;
+        self.garbage_skipped = false;
+
+        loop {
+            if let Some(response) = self.next_response(&mut decode) {
+                return response;
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => return Err(self.timed_out(stale)),
+                },
+                None => None,
+            };
+
+            self.transport.set_read_timeout(timeout)?;
+
+            let bytes_read = match self.decoder.read_from(&mut *self.transport) {
+                Err(Error::Io(e))
+                    if matches!(e.kind(), IoErrorKind::TimedOut | IoErrorKind::WouldBlock) =>
+                {
+                    return Err(self.timed_out(stale));
+                }
+                result => result?,
+            };
+
+            if bytes_read == 0 {
+                return Err(Error::ReadZero);
+            }
+        }
+    }
+
+    /// Counts a call which timed out as stale. If garbage was skipped while waiting, it may have
+    /// been the call's response, damaged beyond recognition. If the call was waiting behind stale
+    /// responses, one of those may have been lost that way, and this call's response thrown away
+    /// in its place. Either way the count can't be trusted anymore, so the bus is resynced before
+    /// the next call.
+    fn timed_out(&mut self, stale: bool) -> Error {
+        self.stale_responses += 1;
+        self.needs_resync |= stale || self.garbage_skipped;
+        Error::Timeout
+    }
+}
+
+/// How the response to a call is turned into its result.
+trait Decode {
+    type Output;
+
+    /// Decodes a response as it was received, without its delimiters.
+    fn decode(&mut self, message: &[u8]) -> Result<Self::Output>;
+
+    /// Decodes the data of a response which has passed through middleware.
+    fn decode_data(&mut self, data: Value) -> Result<Self::Output>;
+}
+
+/// Decodes responses into the response type of a call.
+struct Owned<C>(PhantomData<C>);
+
+impl<C: ApiCall> Decode for Owned<C> {
+    type Output = C::Response;
+
+    fn decode(&mut self, message: &[u8]) -> Result<C::Response> {
+        decode_response::<C>(message)
+    }
+
+    fn decode_data(&mut self, data: Value) -> Result<C::Response> {
+        Ok(serde_json::from_value(data)?)
+    }
+}
+
+/// Passes the data of responses to a closure while it is borrowed from the read buffer.
+struct Borrowed<F>(F);
+
+impl<F, O> Decode for Borrowed<F>
+where
+    F: FnMut(ResponseData<'_>) -> Result<O>,
+{
+    type Output = O;
+
+    fn decode(&mut self, message: &[u8]) -> Result<O> {
+        (self.0)(response::decode_borrowed(message)?)
+    }
+
+    fn decode_data(&mut self, data: Value) -> Result<O> {
+        if data.is_null() {
+            return (self.0)(ResponseData::new(None));
+        }
+
+        let data = RawValue::from_string(data.to_string())?;
+        (self.0)(ResponseData::new(Some(&data)))
+    }
+}
+
+/// Waits for the rate limiter, if there is one, to allow every call in a batch.
+fn throttle_batch<T, I>(limiter: Option<&RateLimiter>, calls: I) -> Result<Vec<Call<T>>>
+where
+    T: ApiCall,
+    I: IntoIterator<Item = Call<T>>,
+{
+    let calls = calls.into_iter().collect::<Vec<_>>();
+
+    if let Some(limiter) = limiter {
+        for call in &calls {
+            limiter.acquire(call.device_id(), None)?;
+        }
+    }
+
+    Ok(calls)
+}
+
+/// Serializes a message onto the end of `buffer`, surrounded by its delimiters.
+///
+/// The message is serialized straight into the buffer. Once it has grown past the maximum size, the
+/// rest of the message is only counted, so that a message which is far too long is rejected without
+/// ever being held in memory as a whole.
+pub(crate) fn encode_message<M: Serialize + ?Sized>(
+    buffer: &mut Vec<u8>,
+    message: &M,
+    max_message_size: usize,
+) -> Result<()> {
+    let start = buffer.len();
+    buffer.push(b'\0');
+
+    let mut writer = LimitedWriter {
+        buffer: &mut *buffer,
+        limit: max_message_size,
+        length: 0,
+    };
+
+    let result = serde_json::to_writer(&mut writer, message);
+    let length = writer.length;
+
+    if let Err(e) = result {
+        buffer.truncate(start);
+        return Err(e.into());
+    }
+
+    if length > max_message_size {
+        buffer.truncate(start);
+
+        return Err(Error::MessageLengthExceeded {
+            kind: MessageKind::Call,
+            length,
+            max: max_message_size,
+        });
+    }
+
+    buffer.push(b'\0');
+
+    Ok(())
+}
+
+/// Appends a message which is already serialized onto the end of `buffer`, surrounded by its
+/// delimiters.
+pub(crate) fn encode_raw_message(buffer: &mut Vec<u8>, message: &[u8]) {
+    buffer.reserve(message.len() + 2);
+    buffer.push(b'\0');
+    buffer.extend_from_slice(message);
+    buffer.push(b'\0');
+}
+
+/// A writer which appends to a vector up to a limit, and only counts the bytes written after that.
+struct LimitedWriter<'a> {
+    buffer: &'a mut Vec<u8>,
+    limit: usize,
+    length: usize,
+}
+
+impl Write for LimitedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.length += buf.len();
+
+        if self.length <= self.limit {
+            self.buffer.extend_from_slice(buf);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Deserializes the response to a call from a message without its delimiters.
+pub(crate) fn decode_response<C: ApiCall>(message: &[u8]) -> Result<C::Response> {
+    serde_json::from_slice::<Response<C>>(message)
+        .map_err(Error::from)?
+        .into()
+}
+
+/// Returns whether an error means that the transport of a bus has stopped working, and has to be
+/// reopened before any more calls can be made on it.
+fn is_disconnect(error: &Error) -> bool {
+    match error {
+        Error::ReadZero => true,
+        Error::Io(e) => e.raw_os_error() == Some(libc::EIO),
+        _ => false,
+    }
+}
+
+/// Returns whether reopening a transport failed only because it can't be reopened at all, in which
+/// case the error which made the bus try is the one worth returning.
+fn is_unsupported(error: &Error) -> bool {
+    matches!(error, Error::Io(e) if e.kind() == IoErrorKind::Unsupported)
+}
+
+/// The transport of a bus which has been closed, which fails every read and write.
+#[derive(Debug)]
+struct Closed;
+
+impl Read for Closed {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            IoErrorKind::NotConnected,
+            "bus has been closed",
+        ))
+    }
+}
+
+impl Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            IoErrorKind::NotConnected,
+            "bus has been closed",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Closed {}
+
+impl<T: ?Sized> Debug for Connection<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("max_message_size", &self.max_message_size)
+            .field("timeout", &self.timeout)
+            .field("nonblocking", &self.nonblocking)
+            .field("stale_responses", &self.stale_responses)
+            .field("garbage_skipped", &self.garbage_skipped)
+            .field("needs_resync", &self.needs_resync)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "mock")]
+    use crate::fault::{Fault, FaultyTransport};
+    #[cfg(feature = "mock")]
+    use crate::mock::MockBus;
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    use crate::transcript::Recorder;
+    #[cfg(feature = "mock")]
+    use crate::types::DeviceDescriptor;
+    #[cfg(feature = "mock")]
+    use crate::{
+        call::{Invoke, List},
+        response::Return,
+    };
+    #[cfg(feature = "mock")]
+    use std::borrow::Cow;
+
+    /// Adds a device with the given methods to a mock bus, each returning its own value.
+    #[cfg(feature = "mock")]
+    fn add_device(mock: &MockBus, methods: &[(&str, i32)]) -> uuid::Uuid {
+        let id = uuid::Uuid::new_v4();
+
+        mock.add_device(
+            DeviceDescriptor {
+                device_id: id,
+                type_names: Box::new(["test".into()]),
+            },
+            [],
+        );
+
+        for &(name, value) in methods {
+            mock.returns(id, name, value).unwrap();
+        }
+
+        id
+    }
+
+    #[cfg(feature = "mock")]
+    fn invoke<'a>(id: uuid::Uuid, name: &'a str) -> Call<Invoke<'a, i32>> {
+        Call::invoke(id, name, &[])
+    }
+
+    #[test]
+    fn closing_delimiter_in_its_own_read() {
+        let mut decoder = FrameDecoder::new();
+
+        decoder.push(b"\0{\"a\":1}");
+        assert_eq!(decoder.next_frame().unwrap(), None);
+
+        decoder.push(b"\0");
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"{\"a\":1}"[..]));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let mut decoder = FrameDecoder::new();
+
+        decoder.push(b"\0{\"a\":1}\0\0{\"b\":2}\0");
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"{\"a\":1}"[..]));
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"{\"b\":2}"[..]));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn delimiters_split_across_reads() {
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+
+        for piece in [&b"\0{\"a\""[..], b":1}\0", b"\0", b"{\"b\":2}", b"\0"] {
+            decoder.push(piece);
+
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame.to_vec());
+            }
+        }
+
+        assert_eq!(frames, [&b"{\"a\":1}"[..], b"{\"b\":2}"]);
+    }
+
+    #[test]
+    fn recovers_after_message_too_long() {
+        let mut decoder = FrameDecoder::with_max_len(8);
+
+        decoder.push(b"\0{\"long\":\"");
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        decoder.push(b"message\"}\0\0{\"a\":1}\0");
+
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(Error::MessageLengthExceeded {
+                length: 18,
+                max: 8,
+                ..
+            })
+        ));
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"{\"a\":1}"[..]));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn damaged_response_answers_its_call() {
+        let mock = MockBus::new();
+        let transport = FaultyTransport::new(mock.transport()).schedule(1, Fault::Corrupt(1));
+        let bus = DeviceBus::with_transport(transport);
+
+        assert!(bus.call(Call::list()).is_ok());
+        assert!(matches!(bus.call(Call::list()), Err(Error::Json(_))));
+
+        for _ in 0..3 {
+            assert!(bus.call(Call::list()).is_ok());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn call_with_borrows_from_response() {
+        for with_middleware in [false, true] {
+            let mock = MockBus::new();
+            let id = add_device(&mock, &[]);
+            mock.returns(id, "getName", "robot").unwrap();
+            mock.returns(id, "getLabel", "line\nbreak").unwrap();
+
+            let bus = mock.bus();
+
+            if with_middleware {
+                bus.add_middleware(middleware::from_fn(|request, mut next| next.run(request)));
+            }
+
+            let types = bus
+                .call_with(Call::list(), |data| {
+                    let response::ListRef(list) = data.deserialize()?;
+                    let type_names = &list[0].type_names;
+
+                    assert!(matches!(type_names[..], [Cow::Borrowed("test")]));
+                    Ok(type_names.len())
+                })
+                .unwrap();
+            assert_eq!(types, 1);
+
+            let name = bus.call_with(invoke(id, "getName"), |data| {
+                let Return(name) = data.deserialize::<Return<&str>>()?;
+                Ok(name.to_owned())
+            });
+            assert_eq!(name.unwrap(), "robot");
+
+            // A string which needs unescaping can't be borrowed as a `&str`.
+            let label = bus.call_with(invoke(id, "getLabel"), |data| {
+                data.deserialize::<Return<&str>>().map(|_| ())
+            });
+            assert!(matches!(label, Err(Error::Json(_))));
+
+            // An error response fails the call without the response being decoded.
+            let missing = bus.call_with(invoke(id, "missing"), |_| -> Result<()> {
+                panic!("error response was decoded")
+            });
+            assert!(matches!(missing, Err(Error::Api(_))));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn long_call_fails_alone_in_batch() {
+        let mock = MockBus::new();
+        let id = add_device(&mock, &[("first", 1), ("last", 3)]);
+        let bus = Builder::new().max_message_size(256).build(mock.transport());
+        let long_name = "x".repeat(256);
+
+        let results = bus
+            .call_batch([
+                invoke(id, "first"),
+                invoke(id, &long_name),
+                invoke(id, "last"),
+            ])
+            .unwrap();
+
+        assert!(matches!(results[0], Ok(Return(1))));
+        assert!(matches!(
+            results[1],
+            Err(Error::MessageLengthExceeded {
+                kind: MessageKind::Call,
+                ..
+            })
+        ));
+        assert!(matches!(results[2], Ok(Return(3))));
+        assert_eq!(mock.calls().len(), 2);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn try_read_message_is_none_until_response_arrives() {
+        let mock = MockBus::new();
+        let id = add_device(&mock, &[]);
+        let tty = mock.tty().unwrap();
+        tty.delay_responses(Duration::from_millis(100));
+
+        let bus = DeviceBus::with_transport(Tty::open(tty.path()).unwrap());
+        bus.set_nonblocking(true).unwrap();
+
+        assert!(bus.send(Call::list()).unwrap());
+        assert!(bus.try_read_message::<List>().unwrap().is_none());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let response::List(list) = loop {
+            if let Some(response) = bus.try_read_message::<List>().unwrap() {
+                break response;
+            }
+
+            assert!(Instant::now() < deadline, "response never arrived");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        assert_eq!(list[0].device_id, id);
+        assert!(bus.try_read_message::<List>().unwrap().is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn recovers_after_response_is_lost_as_garbage() {
+        let mock = MockBus::new();
+        let tty = mock.tty().unwrap();
+        let transport =
+            FaultyTransport::new(Tty::open(tty.path()).unwrap()).schedule(1, Fault::Corrupt(0));
+        let bus = Builder::new()
+            .timeout(Duration::from_millis(200))
+            .build(transport);
+
+        assert!(bus.call(Call::list()).is_ok());
+        assert!(matches!(bus.call(Call::list()), Err(Error::Timeout)));
+
+        for _ in 0..3 {
+            assert!(bus.call(Call::list()).is_ok());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn failed_reconnect_returns_original_error() {
+        let mock = MockBus::new();
+        let transport = FaultyTransport::new(mock.transport()).schedule(0, Fault::Eof);
+        let bus = Builder::new().reconnect(true).build(transport);
+
+        assert!(matches!(bus.call(Call::list()), Err(Error::ReadZero)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn recorder_reconnects() {
+        let mock = MockBus::new();
+        let tty = mock.tty().unwrap();
+        let transport =
+            FaultyTransport::new(Tty::open(tty.path()).unwrap()).schedule(0, Fault::Eof);
+        let bus = Builder::new()
+            .reconnect(true)
+            .build(Recorder::new(transport, io::sink()));
+
+        assert!(bus.call(Call::list()).is_ok());
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn reconnected_bus_stays_registered() {
+        let mock = MockBus::new();
+        let tty = mock.tty().unwrap();
+        let mut bus = Builder::new().reconnect(true).open(tty.path()).unwrap();
+        bus.set_nonblocking(true).unwrap();
+
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(4);
+        poll.registry()
+            .register(&mut bus, Token(7), Interest::READABLE)
+            .unwrap();
+
+        let old_fd = bus.raw_fd().unwrap();
+        bus.connection().reconnect(&Error::ReadZero).unwrap();
+        assert_ne!(bus.raw_fd().unwrap(), old_fd);
+
+        bus.send(Call::list()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let response = loop {
+            poll.poll(&mut events, Some(deadline - Instant::now()))
+                .unwrap();
+            assert!(!events.is_empty(), "no event from the new console");
+            assert!(events.iter().all(|event| event.token() == Token(7)));
+
+            if let Some(response) = bus.try_read_message::<List>().unwrap() {
+                break response;
+            }
+        };
+
+        assert!(response.0.is_empty());
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn long_garbage_is_not_a_response() {
+        let mock = MockBus::new();
+        let id = add_device(&mock, &[("first", 1), ("second", 2)]);
+        let tty = mock.tty().unwrap();
+        let bus = DeviceBus::new(tty.path()).unwrap();
+
+        tty.inject(&[b'x'; 5000]).unwrap();
+
+        assert_eq!(bus.call(invoke(id, "first")).unwrap(), Return(1));
+        assert_eq!(bus.call(invoke(id, "second")).unwrap(), Return(2));
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn sync_bus_answers_every_thread_with_its_own_response() {
+        let mock = MockBus::new();
+        let id = add_device(&mock, &[]);
+        mock.on_invoke(id, "echo", |parameters| Ok(parameters[0].clone()));
+
+        let tty = mock.tty().unwrap();
+        let bus = SyncDeviceBus::new(tty.path()).unwrap();
+
+        let threads = (0..8)
+            .map(|thread| {
+                let bus = bus.clone();
+
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        let value = thread * 100 + i;
+                        let response =
+                            bus.call(Call::<Invoke<'_, i32>>::invoke(id, "echo", &[&value]));
+
+                        assert_eq!(response.unwrap(), Return(value));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
 }
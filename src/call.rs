@@ -7,7 +7,19 @@ use std::marker::PhantomData;
 
 pub trait ApiCall: sealed::Sealed {
     const KIND: &str;
+    /// The `type` field of a successful response to this call.
+    const RESPONSE_KIND: &str;
     type Response: DeserializeOwned + 'static;
+
+    /// The ID of the device the call is made on, if it is made on one.
+    fn device_id(&self) -> Option<uuid::Uuid> {
+        None
+    }
+
+    /// The name of the method invoked by the call, if it invokes one.
+    fn method_name(&self) -> Option<&str> {
+        None
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
@@ -19,6 +31,23 @@ impl<T: ApiCall + Serialize> Call<T> {
     }
 }
 
+impl<T: ApiCall> Call<T> {
+    /// The `type` field of the call.
+    pub fn kind(&self) -> &'static str {
+        T::KIND
+    }
+
+    /// The ID of the device the call is made on, if it is made on one.
+    pub fn device_id(&self) -> Option<uuid::Uuid> {
+        self.0.device_id()
+    }
+
+    /// The name of the method invoked by the call, if it invokes one.
+    pub fn method_name(&self) -> Option<&str> {
+        self.0.method_name()
+    }
+}
+
 impl<T: ApiCall + Serialize> Serialize for Call<T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -71,6 +100,7 @@ pub struct List;
 
 impl ApiCall for List {
     const KIND: &'static str = "list";
+    const RESPONSE_KIND: &'static str = "list";
     type Response = response::List;
 }
 
@@ -83,7 +113,12 @@ pub struct Methods {
 
 impl ApiCall for Methods {
     const KIND: &'static str = "methods";
+    const RESPONSE_KIND: &'static str = "methods";
     type Response = response::Methods;
+
+    fn device_id(&self) -> Option<uuid::Uuid> {
+        Some(self.device_id)
+    }
 }
 
 #[derive(Copy, Clone, Default, Serialize)]
@@ -98,7 +133,16 @@ pub struct Invoke<'a, R> {
 
 impl<R: DeserializeOwned + 'static> ApiCall for Invoke<'_, R> {
     const KIND: &'static str = "invoke";
+    const RESPONSE_KIND: &'static str = "result";
     type Response = response::Return<R>;
+
+    fn device_id(&self) -> Option<uuid::Uuid> {
+        Some(self.device_id)
+    }
+
+    fn method_name(&self) -> Option<&str> {
+        Some(self.name)
+    }
 }
 
 mod sealed {
@@ -1,7 +1,9 @@
 // TODO: Better documentation. The documentation is very badly written at the moment because I was
 // trying to get the point across. Needs rewording and examples.
 
-use crate::bus::DeviceBus;
+#[cfg(feature = "async")]
+use crate::async_bus::AsyncRpcBus;
+use crate::bus::RpcBus;
 use crate::call::Call;
 use crate::error::Result;
 use crate::types::{Direction, ImportFileInfo, RobotActionResult, MoveDirection, RotationDirection};
@@ -14,6 +16,7 @@ use uuid::Uuid;
 // TODO: Turn this into a procedural macro
 macro_rules! interface {
     (
+        #[interface(async = $async_name:ident)]
         $(#[$outer:meta])*
         $vis:vis trait $trait_name:ident {
             $(const $assoc_const:ident : $assoc_ty:ty;)*
@@ -38,6 +41,23 @@ macro_rules! interface {
                     where ($($ret_ty)?): ::serde::de::DeserializeOwned + 'static;
             )*
         }
+
+        #[cfg(feature = "async")]
+        $(#[$outer])*
+        #[doc = ""]
+        #[doc = concat!("The asynchronous counterpart of [`", stringify!($trait_name), "`].")]
+        $vis trait $async_name: $crate::device::RpcDevice {
+            $(
+                const $assoc_const: $assoc_ty;
+            )*
+
+            $(
+                $(#[$inner])*
+                #[allow(unused_parens)]
+                fn $fn_name$(<$($generic),+>)?(&self, $($($param_name: $param_ty),*)?) -> impl ::core::future::Future<Output = $crate::error::Result<($($ret_ty)?)>> + ::core::marker::Send
+                    where ($($ret_ty)?): ::serde::de::DeserializeOwned + 'static;
+            )*
+        }
     };
 }
 
@@ -50,6 +70,7 @@ macro_rules! device {
         $vis:vis struct $device_name:ident;
 
         $(
+            $(#[device(async = $async_name:ident)])?
             impl $trait_name:ident {
                 $(
                     #[device(invoke = $invoke_name:literal)]
@@ -60,26 +81,28 @@ macro_rules! device {
 
     ) => {
         $(#[$doc])*
-        $vis struct $device_name(::uuid::Uuid, $crate::bus::DeviceBus);
+        $vis struct $device_name<B = $crate::bus::DeviceBus>(::uuid::Uuid, B);
 
-        impl $crate::device::RpcDevice for $device_name {
+        impl<B: ::core::clone::Clone> $crate::device::RpcDevice for $device_name<B> {
             const IDENTIFIER: &'static ::core::primitive::str = $identifier;
 
-            fn new(id: ::uuid::Uuid, bus: &$crate::bus::DeviceBus) -> Self {
-                Self(id, bus.clone())
+            type Bus = B;
+
+            fn new(id: ::uuid::Uuid, bus: &B) -> Self {
+                Self(id, ::core::clone::Clone::clone(bus))
             }
 
             fn id(&self) -> ::uuid::Uuid {
                 self.0
             }
 
-            fn bus(&self) -> &$crate::bus::DeviceBus {
+            fn bus(&self) -> &B {
                 &self.1
             }
         }
 
         $(
-            impl $trait_name for $device_name {
+            impl<B: $crate::bus::RpcBus> $trait_name for $device_name<B> {
                 $(
                     #[allow(non_snake_case)]
                     #[allow(unused_parens)]
@@ -90,18 +113,64 @@ macro_rules! device {
                     }
                 )+
             }
+
+            $crate::__device_async_impl! {
+                [$($async_name)?] $device_name {
+                    $(
+                        #[device(invoke = $invoke_name)]
+                        fn $fn_name $(<$($generic),+>)? (&self $(, $($param_name : $param_ty),*)?) $(-> $ret_ty)?;
+                    )+
+                }
+            }
         )+
     };
 }
 
+// Implements the asynchronous counterpart of an interface for a device, if one was given. This is a
+// separate macro so that whether the implementation exists depends on this crate's `async` feature,
+// rather than on the features of the crate invoking `device!`.
+#[cfg(feature = "async")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __device_async_impl {
+    ([] $($rest:tt)*) => {};
+    (
+        [$async_name:ident] $device_name:ident {
+            $(
+                #[device(invoke = $invoke_name:literal)]
+                fn $fn_name:ident $(<$($generic:ident),+>)? (&self $(, $($param_name:ident : $param_ty:ty),*)?) $(-> $ret_ty:ty)?;
+            )+
+        }
+    ) => {
+        impl<B: $crate::async_bus::AsyncRpcBus> $async_name for $device_name<B> {
+            $(
+                #[allow(non_snake_case)]
+                #[allow(unused_parens)]
+                fn $fn_name$(<$($generic),+>)?(&self, $($($param_name: $param_ty),*)?) -> impl ::core::future::Future<Output = $crate::error::Result<($($ret_ty)?)>> + ::core::marker::Send
+                    where ($($ret_ty)?): ::serde::de::DeserializeOwned + 'static
+                {
+                    $crate::async_bus::invoke(self.0, &self.1, $invoke_name, &[$($(&$param_name as &dyn ::erased_serde::Serialize),*)?])
+                }
+            )+
+        }
+    };
+}
+
+#[cfg(not(feature = "async"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __device_async_impl {
+    ($($rest:tt)*) => {};
+}
+
 // This function is simply here to cut down on possible code duplication, since each
 // call which shares the same return type can share the same monomorphized version of this
 // function.
 #[doc(hidden)]
 #[inline(never)]
-pub fn invoke<R: DeserializeOwned + 'static>(
+pub fn invoke<B: RpcBus, R: DeserializeOwned + 'static>(
     id: Uuid,
-    bus: &DeviceBus,
+    bus: &B,
     method_name: &str,
     params: &[&dyn ErasedSerialize],
 ) -> Result<R> {
@@ -113,12 +182,16 @@ pub fn invoke<R: DeserializeOwned + 'static>(
 pub trait RpcDevice {
     const IDENTIFIER: &'static str;
 
-    fn new(id: Uuid, bus: &DeviceBus) -> Self;
+    /// The kind of bus the device was found on.
+    type Bus: Clone;
+
+    fn new(id: Uuid, bus: &Self::Bus) -> Self;
     fn id(&self) -> Uuid;
-    fn bus(&self) -> &DeviceBus;
+    fn bus(&self) -> &Self::Bus;
 }
 
 interface! {
+    #[interface(async = AsyncEnergyStorageInterface)]
     /// An interface between an energy storage device and the HLAPI.
     pub trait EnergyStorageInterface {
         /// Retrieves the current amount of energy stored in FE.
@@ -137,6 +210,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncItemHandlerInterface)]
     /// An interface between item storage devices or blocks and the HLAPI.
     pub trait ItemHandlerInterface {
         /// Returns a signed 32-bit integer that represents the slots in the storage
@@ -154,6 +228,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncRedstoneInterface)]
     /// An interface between redstone signal sending and receiving devices and the HLAPI
     pub trait RedstoneInterface {
         /// Returns a signed 32-bit integer that represents the strength of the redstone input on
@@ -171,6 +246,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncSoundInterface)]
     /// An interface between sound-making devices and the HALPI
     pub trait SoundInterface {
         /// Returns a slice of sound effect names matching the given name. The length of the slice
@@ -183,6 +259,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncFileImportExportInterface)]
     /// An interface for transferring files between a user's real computer and the HLAPI
     pub trait FileImportExportInterface {
         /// Requests the start of a file import, returning true if a file can be imported.
@@ -214,6 +291,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncBlockOperationsInterface)]
     /// An interface between devices which carry out block operations and the HLAPI
     pub trait BlockOperationsInterface {
         /// Mines the adjacent block on the given side. Returns true if the block was able to be
@@ -234,6 +312,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncInventoryOperationsInterface)]
     /// An interface between devices which carry out robot inventory operations and the HLAPI
     pub trait InventoryOperationsInterface {
         /// Attempts to move the given number of items from one robot inventory slot into another
@@ -260,6 +339,7 @@ interface! {
 }
 
 interface! {
+    #[interface(async = AsyncRobotInterface)]
     /// An interface between robots and the HLAPI.
     pub trait RobotInterface {
         /// Returns the amount of FE stored in a robot.
@@ -301,6 +381,7 @@ device! {
     /// A device that can interact with redstone in the world.
     pub struct RedstoneDevice;
 
+    #[device(async = AsyncRedstoneInterface)]
     impl RedstoneInterface {
         #[device(invoke = "getRedstoneInput")]
         fn get_redstone_input(&self, side: Direction) -> i32;
@@ -318,6 +399,7 @@ device! {
     /// A device that allows a computer or robot to play sounds.
     pub struct SoundCard;
 
+    #[device(async = AsyncSoundInterface)]
     impl SoundInterface {
         #[device(invoke = "findSound")]
         fn find_sound(&self, name: &str) -> Box<[Box<str>]>;
@@ -332,6 +414,7 @@ device! {
     /// A device that allows importing and exporting of files from the player's computer.
     pub struct FileImportExportCard;
 
+    #[device(async = AsyncFileImportExportInterface)]
     impl FileImportExportInterface {
         #[device(invoke = "requestImportFile")]
         fn request_import_file(&self) -> bool;
@@ -361,6 +444,7 @@ device! {
     /// A module that allows interaction with inventories in the the world.
     pub struct InventoryOperationsModule;
 
+    #[device(async = AsyncInventoryOperationsInterface)]
     impl InventoryOperationsInterface {
         #[device(invoke = "move")]
         fn move_item(&self, from: i32, into: i32, count: i32);
@@ -384,6 +468,7 @@ device! {
     /// A module that allows interaction with blocks in the world.
     pub struct BlockOperationsModule;
 
+    #[device(async = AsyncBlockOperationsInterface)]
     impl BlockOperationsInterface {
         #[device(invoke = "excavate")]
         fn excavate(&self, side: Direction) -> bool;
@@ -403,6 +488,7 @@ device! {
     #[device(identifier = "robot")]
     pub struct RobotDevice;
 
+    #[device(async = AsyncRobotInterface)]
     impl RobotInterface {
         #[device(invoke = "getEnergyStored")]
         fn get_energy_stored(&self) -> i32;
@@ -436,9 +522,11 @@ device! {
     }
 }
 
-impl RobotDevice {
+impl<B> RobotDevice<B> {
     const WAIT_DURATION: Duration = Duration::from_millis(100);
+}
 
+impl<B: RpcBus> RobotDevice<B> {
     /// Attempts to queue an action which moves the robot in the given direction, waiting until the
     /// action can be successfully queued.
     pub fn wait_queue_move(&self, direction: MoveDirection) -> Result<()> {
@@ -487,3 +575,54 @@ impl RobotDevice {
         Ok(result == RobotActionResult::Success)
     }
 }
+
+#[cfg(feature = "async")]
+impl<B: AsyncRpcBus> RobotDevice<B> {
+    /// Attempts to queue an action which moves the robot in the given direction, waiting until the
+    /// action can be successfully queued.
+    pub async fn wait_queue_move_async(&self, direction: MoveDirection) -> Result<()> {
+        while !AsyncRobotInterface::queue_move(self, direction).await? {
+            tokio::time::sleep(Self::WAIT_DURATION).await;
+        }
+
+        Ok(())
+    }
+
+    /// Attempts to queue an action which moves the robot in the given direction, waiting until the
+    /// action has completed.
+    pub async fn wait_move_async(&self, direction: MoveDirection) -> Result<bool> {
+        self.wait_queue_move_async(direction).await?;
+        self.wait_for_last_action_async().await
+    }
+
+    /// Attempts to queue an action which turns the robot in the given direction, waiting until the
+    /// action can be successfully queued.
+    pub async fn wait_queue_turn_async(&self, direction: RotationDirection) -> Result<()> {
+        while !AsyncRobotInterface::queue_turn(self, direction).await? {
+            tokio::time::sleep(Self::WAIT_DURATION).await;
+        }
+
+        Ok(())
+    }
+
+    /// Attempts to queue an action which turns the robot in the given direction, waiting until the
+    /// action has completed.
+    pub async fn wait_turn_async(&self, direction: RotationDirection) -> Result<bool> {
+        self.wait_queue_turn_async(direction).await?;
+        self.wait_for_last_action_async().await
+    }
+
+    /// Waits for the previous action to complete, returning whether the action completed
+    /// successfully.
+    async fn wait_for_last_action_async(&self) -> Result<bool> {
+        let id = AsyncRobotInterface::get_last_action_id(self).await?;
+
+        let mut result = AsyncRobotInterface::get_action_result(self, id).await?;
+        while result == RobotActionResult::Incomplete {
+            tokio::time::sleep(Self::WAIT_DURATION).await;
+            result = AsyncRobotInterface::get_action_result(self, id).await?;
+        }
+
+        Ok(result == RobotActionResult::Success)
+    }
+}
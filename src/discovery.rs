@@ -0,0 +1,343 @@
+//! Finding the device bus to use without hard-coding its path.
+//!
+//! [`DeviceBus::open_default`](crate::DeviceBus::open_default) looks for a [`BusAddress`] in these
+//! places, using the first one which is set:
+//!
+//! 1. the `OC2_HLAPI_BUS` environment variable,
+//! 2. the `bus` setting in the `/etc/oc2-hlapi.conf` config file,
+//! 3. the default [`Multiplexer`](crate::mux::Multiplexer) socket and then the OC2 console, the
+//!    first of which can be opened.
+//!
+//! The config file is made of `key = value` lines, and `#` starts a comment. Besides `bus`, it can
+//! set the `secret` used for a TCP bridge, which can also be set with the
+//! `OC2_HLAPI_BRIDGE_SECRET` environment variable.
+
+use crate::error::{Error, Result};
+use crate::mux::{self, DEFAULT_SOCKET_PATH};
+use crate::transcript::Replay;
+use crate::transport::{Transport, Tty};
+use std::convert::Infallible;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The environment variable which overrides the address of the device bus.
+pub const BUS_VAR: &str = "OC2_HLAPI_BUS";
+
+/// The environment variable which holds the secret for connecting to a TCP bridge.
+pub const SECRET_VAR: &str = "OC2_HLAPI_BRIDGE_SECRET";
+
+/// The path of the config file which sets the address of the device bus.
+pub const CONFIG_PATH: &str = "/etc/oc2-hlapi.conf";
+
+/// The path of the console which the OC2 VM exposes the HLAPI on.
+pub const DEFAULT_TTY_PATH: &str = "/dev/hvc0";
+
+/// Where a device bus can be opened.
+///
+/// Addresses are written as `unix:<path>` for a [`Multiplexer`](crate::mux::Multiplexer) socket,
+/// `tcp:<host>:<port>` for a TCP bridge, `replay:<path>` for a transcript made by a
+/// [`Recorder`](crate::transcript::Recorder), and `tty:<path>` or just `<path>` for a console. A
+/// plain path which turns out to be a Unix socket is connected to as a multiplexer.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum BusAddress {
+    /// A console, such as `/dev/hvc0`.
+    Tty(PathBuf),
+    /// The Unix socket of a multiplexer.
+    Unix(PathBuf),
+    /// The address of a TCP bridge.
+    Tcp(String),
+    /// A transcript to replay.
+    Replay(PathBuf),
+}
+
+impl BusAddress {
+    /// Opens the transport at this address. Connecting to a TCP bridge requires a secret.
+    pub fn open(
+        &self,
+        secret: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<Box<dyn Transport + Send>> {
+        let transport: Box<dyn Transport + Send> = match self {
+            Self::Tty(path) if is_socket(path) => Box::new(UnixStream::connect(path)?),
+            Self::Tty(path) => Box::new(Tty::open(path)?),
+            Self::Unix(path) => Box::new(UnixStream::connect(path)?),
+            Self::Tcp(addr) => {
+                let secret = secret.ok_or_else(|| {
+                    Error::Handshake(
+                        format!("no secret was given with {SECRET_VAR} or in {CONFIG_PATH}").into(),
+                    )
+                })?;
+
+                Box::new(mux::connect_tcp(addr.as_str(), secret, timeout)?)
+            }
+            Self::Replay(path) => Box::new(Replay::open(path)?),
+        };
+
+        Ok(transport)
+    }
+}
+
+impl From<&str> for BusAddress {
+    fn from(value: &str) -> Self {
+        match value.split_once(':') {
+            Some(("tty", path)) => Self::Tty(path.into()),
+            Some(("unix", path)) => Self::Unix(path.into()),
+            Some(("tcp", addr)) => Self::Tcp(addr.into()),
+            Some(("replay", path)) => Self::Replay(path.into()),
+            _ => Self::Tty(value.into()),
+        }
+    }
+}
+
+impl FromStr for BusAddress {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        Ok(Self::from(s))
+    }
+}
+
+impl Display for BusAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tty(path) => write!(f, "tty:{}", path.display()),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Self::Replay(path) => write!(f, "replay:{}", path.display()),
+        }
+    }
+}
+
+/// Opens the device bus found as described in the [module documentation](self).
+pub(crate) fn open_default(timeout: Option<Duration>) -> Result<Box<dyn Transport + Send>> {
+    let config = Config::load();
+    let secret = env::var(SECRET_VAR)
+        .ok()
+        .or_else(|| config.as_ref().ok()?.secret.clone());
+
+    search(env::var_os(BUS_VAR), config, |address| {
+        address.open(secret.as_deref(), timeout)
+    })
+}
+
+/// Opens the first address found with `open`, given the value of [`BUS_VAR`] and the config file.
+fn search<T>(
+    bus_var: Option<OsString>,
+    config: io::Result<Config>,
+    mut open: impl FnMut(&BusAddress) -> Result<T>,
+) -> Result<T> {
+    let (config, config_error) = match config {
+        Ok(config) => (config, None),
+        Err(e) => (Config::default(), Some(e)),
+    };
+
+    let mut tried = Vec::new();
+
+    let addresses = if let Some(address) = bus_var {
+        vec![(BUS_VAR, BusAddress::from(&*address.to_string_lossy()))]
+    } else {
+        tried.push(format!("{BUS_VAR} is not set"));
+
+        if let Some(address) = config.bus {
+            vec![(CONFIG_PATH, BusAddress::from(&*address))]
+        } else {
+            tried.push(match config_error {
+                Some(e) => format!("{CONFIG_PATH}: {e}"),
+                None => format!("{CONFIG_PATH} doesn't set `bus`"),
+            });
+
+            vec![
+                ("default", BusAddress::Unix(DEFAULT_SOCKET_PATH.into())),
+                ("default", BusAddress::Tty(DEFAULT_TTY_PATH.into())),
+            ]
+        }
+    };
+
+    for (source, address) in addresses {
+        match open(&address) {
+            Ok(transport) => return Ok(transport),
+            Err(e) => tried.push(format!("{address} from {source}: {e}")),
+        }
+    }
+
+    Err(Error::BusNotFound(tried.into()))
+}
+
+#[derive(Default)]
+struct Config {
+    bus: Option<String>,
+    secret: Option<String>,
+}
+
+impl Config {
+    fn load() -> io::Result<Self> {
+        fs::read_to_string(CONFIG_PATH).map(|contents| Self::parse(&contents))
+    }
+
+    fn parse(contents: &str) -> Self {
+        let mut config = Self::default();
+
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            let value = Some(value.trim().to_owned());
+
+            match key.trim() {
+                "bus" => config.bus = value,
+                "secret" => config.secret = value,
+                _ => {}
+            }
+        }
+
+        config
+    }
+}
+
+fn is_socket(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(bus: Option<&str>) -> io::Result<Config> {
+        Ok(Config {
+            bus: bus.map(String::from),
+            secret: None,
+        })
+    }
+
+    /// Searches for a bus which can't be opened, returning the addresses tried and the error.
+    fn search_failing(bus_var: Option<&str>, config: io::Result<Config>) -> (Vec<String>, String) {
+        let mut opened = Vec::new();
+        let result = search(bus_var.map(OsString::from), config, |address| {
+            opened.push(address.to_string());
+            Err::<(), _>(Error::Io(io::ErrorKind::NotFound.into()))
+        });
+
+        (opened, result.unwrap_err().to_string())
+    }
+
+    #[test]
+    fn addresses_are_parsed_by_prefix() {
+        let cases = [
+            ("tty:/dev/hvc1", BusAddress::Tty("/dev/hvc1".into())),
+            (
+                "unix:/run/bus.sock",
+                BusAddress::Unix("/run/bus.sock".into()),
+            ),
+            (
+                "tcp:localhost:4242",
+                BusAddress::Tcp("localhost:4242".into()),
+            ),
+            (
+                "replay:calls.jsonl",
+                BusAddress::Replay("calls.jsonl".into()),
+            ),
+            ("/dev/hvc0", BusAddress::Tty("/dev/hvc0".into())),
+            ("other:path", BusAddress::Tty("other:path".into())),
+        ];
+
+        for (text, address) in cases {
+            assert_eq!(text.parse::<BusAddress>().unwrap(), address);
+        }
+    }
+
+    #[test]
+    fn addresses_display_as_they_are_parsed() {
+        for text in [
+            "tty:/dev/hvc1",
+            "unix:/run/bus.sock",
+            "tcp:[::1]:4242",
+            "replay:a.jsonl",
+        ] {
+            assert_eq!(BusAddress::from(text).to_string(), text);
+        }
+    }
+
+    #[test]
+    fn config_skips_comments_and_unknown_keys() {
+        let config = Config::parse(
+            "# the bus\n bus = tcp:10.0.0.2:4242 # bridge\ncolour = blue\nsecret=hunter2\n",
+        );
+
+        assert_eq!(config.bus.as_deref(), Some("tcp:10.0.0.2:4242"));
+        assert_eq!(config.secret.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn environment_overrides_config() {
+        let (opened, error) = search_failing(Some("unix:/tmp/env.sock"), config(Some("/dev/hvc1")));
+
+        assert_eq!(opened, ["unix:/tmp/env.sock"]);
+        assert!(error.contains(&format!("unix:/tmp/env.sock from {BUS_VAR}")));
+    }
+
+    #[test]
+    fn config_is_used_without_environment() {
+        let (opened, error) = search_failing(None, config(Some("/dev/hvc1")));
+
+        assert_eq!(opened, ["tty:/dev/hvc1"]);
+        assert!(error.contains(&format!("{BUS_VAR} is not set")));
+        assert!(error.contains(&format!("tty:/dev/hvc1 from {CONFIG_PATH}")));
+    }
+
+    #[test]
+    fn defaults_are_tried_in_order() {
+        let missing = Err(io::ErrorKind::NotFound.into());
+        let (opened, error) = search_failing(None, missing);
+        let socket = format!("unix:{DEFAULT_SOCKET_PATH}");
+        let tty = format!("tty:{DEFAULT_TTY_PATH}");
+
+        assert_eq!(opened, [socket.as_str(), tty.as_str()]);
+
+        // Every place which was looked in is listed, in the order it was looked in.
+        let places = [
+            format!("{BUS_VAR} is not set"),
+            format!("{CONFIG_PATH}: "),
+            format!("{socket} from default"),
+            format!("{tty} from default"),
+        ];
+        let positions = places.map(|place| error.find(&place).unwrap());
+        assert!(positions.is_sorted());
+
+        let (_, error) = search_failing(None, config(None));
+        assert!(error.contains(&format!("{CONFIG_PATH} doesn't set `bus`")));
+    }
+
+    #[test]
+    fn first_default_which_opens_is_used() {
+        let mut opened = Vec::new();
+        let result = search(None, config(None), |address| {
+            opened.push(address.clone());
+
+            match address {
+                BusAddress::Tty(_) => Ok(()),
+                _ => Err(Error::Io(io::ErrorKind::NotFound.into())),
+            }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(
+            opened,
+            [
+                BusAddress::Unix(DEFAULT_SOCKET_PATH.into()),
+                BusAddress::Tty(DEFAULT_TTY_PATH.into()),
+            ]
+        );
+    }
+}
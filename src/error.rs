@@ -1,4 +1,6 @@
+use serde::{Deserialize, Serialize};
 use serde_json::error::Category as JsonErrorCategory;
+use std::fmt::{self, Display};
 use std::io;
 use thiserror::Error;
 
@@ -7,24 +9,46 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum Error {
-    #[error("message length exceeded {} bytes", crate::bus::MAX_MESSAGE_SIZE)]
-    MessageLengthExceeded,
+    #[error("{kind} of {length} bytes exceeded the maximum message length of {max} bytes")]
+    MessageLengthExceeded {
+        /// Whether the message was being sent or received.
+        kind: MessageKind,
+        /// The length of the message in bytes.
+        length: usize,
+        /// The maximum length of a message in bytes.
+        max: usize,
+    },
     #[error("read zero bytes from device bus")]
     ReadZero,
+    #[error("timed out waiting for a response from the device bus")]
+    Timeout,
     #[error("I/O error: {0}")]
     Io(io::Error),
     #[error("JSON error: {0}")]
     Json(serde_json::Error),
     #[error("HLAPI error: {0}")]
     Api(Box<str>),
+    #[error("bridge handshake failed: {0}")]
+    Handshake(Box<str>),
+    #[error("no device bus could be opened; tried: {}", .0.join("; "))]
+    BusNotFound(Box<[String]>),
 }
 
-impl Error {
-    fn from_io_error(e: io::Error) -> Self {
-        if e.kind() == io::ErrorKind::WriteZero {
-            Self::MessageLengthExceeded
-        } else {
-            Self::Io(e)
+/// The kind of a message, either sent to or received from the HLAPI.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    /// A call sent to the HLAPI.
+    Call,
+    /// A response received from the HLAPI.
+    Response,
+}
+
+impl Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Call => f.write_str("call"),
+            Self::Response => f.write_str("response"),
         }
     }
 }
@@ -32,7 +56,7 @@ impl Error {
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
         match value.classify() {
-            JsonErrorCategory::Io => Self::from_io_error(value.into()),
+            JsonErrorCategory::Io => Self::Io(value.into()),
             _ => Self::Json(value),
         }
     }
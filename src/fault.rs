@@ -0,0 +1,414 @@
+//! Injecting faults into the responses read from a transport, for checking that code using a bus
+//! recovers from them.
+//!
+//! A [`FaultyTransport`] wraps another transport, such as a [`MockTransport`], and changes the
+//! frames read from it: it can delay, split, duplicate, truncate, corrupt, replace or drop them, or
+//! pretend that the transport reached its end. Faults are applied to chosen frames, to frames
+//! picked at random from a seed, or both:
+//!
+//! ```
+//! # use oc2_hlapi::prelude::*;
+//! # use oc2_hlapi::error::Error;
+//! # use oc2_hlapi::fault::{Fault, FaultyTransport};
+//! # use oc2_hlapi::mock::MockBus;
+//! # fn main() -> oc2_hlapi::error::Result<()> {
+//! let mock = MockBus::new();
+//! let transport = FaultyTransport::new(mock.transport())
+//!     .schedule(0, Fault::Replace(br#"{"type":"list","data":42}"#.as_slice().into()))
+//!     .schedule(1, Fault::Eof);
+//! let log = transport.log();
+//! let bus = DeviceBus::with_transport(transport);
+//!
+//! assert!(matches!(bus.call(Call::list()), Err(Error::Json(_))));
+//! assert!(matches!(bus.call(Call::list()), Err(Error::ReadZero)));
+//! assert!(bus.call(Call::list()).is_ok());
+//! assert_eq!(log.faults().len(), 2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every segment of the stream between delimiters counts as a frame, including garbage. Calls are
+//! written to the wrapped transport unchanged.
+//!
+//! [`MockTransport`]: crate::mock::MockTransport
+
+use crate::bus::{encode_raw_message, FrameDecoder};
+use crate::transport::{PollSettings, Termios, Transport};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A change made to a frame read from a [`FaultyTransport`].
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Fault {
+    /// Holds the frame, and every frame after it, back for the given time.
+    Delay(Duration),
+    /// Delivers the frame in pieces of at most the given number of bytes, including its
+    /// delimiters, which are each returned by a separate read.
+    Split(usize),
+    /// Delivers the frame twice.
+    Duplicate,
+    /// Delivers only the opening delimiter and the given number of bytes of the frame, cutting off
+    /// the rest of it. At least the last byte of the frame is always cut off.
+    Truncate(usize),
+    /// Inverts the bits of the byte at the given offset into the frame, wrapping around at its end.
+    /// This usually makes the frame invalid JSON.
+    Corrupt(usize),
+    /// Delivers the given bytes between delimiters instead of the frame. This can be valid JSON
+    /// which doesn't match the response the bus is waiting for.
+    Replace(Box<[u8]>),
+    /// Leaves the frame out completely.
+    Drop,
+    /// Returns zero bytes from the next read, as if the transport had reached its end, and then
+    /// delivers the frame as usual.
+    Eof,
+}
+
+/// A transport which injects [`Fault`]s into the frames read from another transport.
+#[derive(Debug)]
+pub struct FaultyTransport<T> {
+    inner: T,
+    schedule: BTreeMap<u64, Fault>,
+    random: Option<Random>,
+    log: FaultLog,
+    decoder: FrameDecoder,
+    output: VecDeque<Chunk>,
+    frames: u64,
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    /// Wraps a transport without injecting any faults yet.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            schedule: BTreeMap::new(),
+            random: None,
+            log: FaultLog::default(),
+            decoder: FrameDecoder::new(),
+            output: VecDeque::new(),
+            frames: 0,
+            read_timeout: None,
+            nonblocking: false,
+        }
+    }
+
+    /// Applies a fault to the frame with the given index, counting from zero. This takes
+    /// precedence over faults picked at [random](Self::random).
+    pub fn schedule(mut self, frame: u64, fault: Fault) -> Self {
+        self.schedule.insert(frame, fault);
+        self
+    }
+
+    /// Applies one of the given faults, picked at random, to each frame with the given probability.
+    /// The same seed picks the same faults for the same frames every time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `faults` is empty.
+    pub fn random<I>(mut self, seed: u64, probability: f64, faults: I) -> Self
+    where
+        I: IntoIterator<Item = Fault>,
+    {
+        let faults: Box<[Fault]> = faults.into_iter().collect();
+        assert!(!faults.is_empty(), "no faults to pick from");
+
+        self.random = Some(Random {
+            state: seed,
+            probability,
+            faults,
+        });
+        self
+    }
+
+    /// Returns a handle to the log of the faults injected so far, which stays usable after the
+    /// transport was given to a bus.
+    pub fn log(&self) -> FaultLog {
+        self.log.clone()
+    }
+
+    /// Returns a reference to the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwraps the transport, throwing away anything which hasn't been read yet.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Splits the frames received so far off the decoder, and queues them with their faults.
+    fn queue_frames(&mut self) {
+        while let Some(frame) = self.decoder.next_unbounded_frame() {
+            let index = self.frames;
+            self.frames += 1;
+
+            let fault = match self.schedule.remove(&index) {
+                Some(fault) => Some(fault),
+                None => self.random.as_mut().and_then(Random::pick),
+            };
+
+            let Some(fault) = fault else {
+                self.output.push_back(Chunk::data(delimited(frame)));
+                continue;
+            };
+
+            match &fault {
+                Fault::Delay(delay) => self.output.push_back(Chunk {
+                    ready: Some(Instant::now() + *delay),
+                    bytes: delimited(frame),
+                }),
+                Fault::Split(size) => {
+                    for piece in delimited(frame).chunks((*size).max(1)) {
+                        self.output.push_back(Chunk::data(piece.to_vec()));
+                    }
+                }
+                Fault::Duplicate => {
+                    self.output.push_back(Chunk::data(delimited(frame)));
+                    self.output.push_back(Chunk::data(delimited(frame)));
+                }
+                Fault::Truncate(len) => {
+                    let mut bytes = delimited(frame);
+                    bytes.truncate(1 + (*len).min(frame.len() - 1));
+                    self.output.push_back(Chunk::data(bytes));
+                }
+                Fault::Corrupt(offset) => {
+                    let mut bytes = delimited(frame);
+
+                    // Frames between delimiters are never empty.
+                    let len = bytes.len() - 2;
+                    bytes[1 + offset % len] ^= 0xFF;
+                    self.output.push_back(Chunk::data(bytes));
+                }
+                Fault::Replace(replacement) => {
+                    self.output.push_back(Chunk::data(delimited(replacement)));
+                }
+                Fault::Drop => {}
+                Fault::Eof => {
+                    self.output.push_back(Chunk::data(Vec::new()));
+                    self.output.push_back(Chunk::data(delimited(frame)));
+                }
+            }
+
+            self.log.state().push((index, fault));
+        }
+    }
+
+    /// Waits until a chunk which is being held back is ready, for no longer than a read may block.
+    fn wait_until(&self, ready: Instant) -> io::Result<()> {
+        let wait = ready.saturating_duration_since(Instant::now());
+
+        if wait.is_zero() {
+            return Ok(());
+        }
+
+        if self.nonblocking {
+            return Err(IoErrorKind::WouldBlock.into());
+        }
+
+        match self.read_timeout {
+            Some(timeout) if timeout < wait => {
+                sleep(timeout);
+                Err(IoErrorKind::TimedOut.into())
+            }
+            _ => {
+                sleep(wait);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: Transport> Read for FaultyTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ready) = self.output.front().and_then(|chunk| chunk.ready) {
+                self.wait_until(ready)?;
+                self.output[0].ready = None;
+            }
+
+            if let Some(chunk) = self.output.front_mut() {
+                let len = chunk.bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&chunk.bytes[..len]);
+                chunk.bytes.drain(..len);
+
+                // An empty chunk is an injected end of the stream, which is returned once.
+                if chunk.bytes.is_empty() {
+                    self.output.pop_front();
+                }
+
+                return Ok(len);
+            }
+
+            let mut bytes = [0; 4096];
+            let read = self.inner.read(&mut bytes)?;
+
+            if read == 0 {
+                return Ok(0);
+            }
+
+            self.decoder.push(&bytes[..read]);
+            self.queue_frames();
+        }
+    }
+}
+
+impl<T: Transport> Write for FaultyTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    // Readiness of the wrapped transport says nothing about frames which are being held back, so
+    // the file descriptor isn't passed on.
+
+    fn configure(&mut self, f: &mut dyn FnMut(&mut Termios)) -> io::Result<()> {
+        self.inner.configure(f)
+    }
+
+    fn configure_poll(&mut self, f: &mut dyn FnMut(&mut PollSettings)) -> io::Result<()> {
+        self.inner.configure_poll(f)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        T::close(Box::new(self.inner))
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.inner.reopen()?;
+        self.decoder = FrameDecoder::new();
+        self.output.clear();
+
+        Ok(())
+    }
+}
+
+/// The faults injected by a [`FaultyTransport`], as the index of the frame and the fault applied
+/// to it.
+///
+/// Clones of a log share its entries.
+#[derive(Clone, Debug, Default)]
+pub struct FaultLog(Arc<Mutex<Vec<(u64, Fault)>>>);
+
+impl FaultLog {
+    /// Returns the faults injected so far, in the order they were injected.
+    pub fn faults(&self) -> Vec<(u64, Fault)> {
+        self.state().clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, Vec<(u64, Fault)>> {
+        // Entries are only ever pushed whole, so a panic while the log was locked doesn't matter.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Bytes waiting to be read, which can't be read before `ready` if it is set.
+#[derive(Debug)]
+struct Chunk {
+    ready: Option<Instant>,
+    bytes: Vec<u8>,
+}
+
+impl Chunk {
+    fn data(bytes: Vec<u8>) -> Self {
+        Self { ready: None, bytes }
+    }
+}
+
+/// Picks faults for frames at random, with a SplitMix64 generator.
+#[derive(Debug)]
+struct Random {
+    state: u64,
+    probability: f64,
+    faults: Box<[Fault]>,
+}
+
+impl Random {
+    fn pick(&mut self) -> Option<Fault> {
+        // The top 53 bits make a uniformly distributed float in [0, 1).
+        let roll = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+
+        if roll >= self.probability {
+            return None;
+        }
+
+        let index = self.next() % self.faults.len() as u64;
+        Some(self.faults[index as usize].clone())
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+fn delimited(frame: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    encode_raw_message(&mut bytes, frame);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBus;
+
+    const CALL: &[u8] = b"\0{\"type\":\"list\"}\0";
+
+    #[test]
+    fn delayed_frame_is_read_without_blocking_once_ready() {
+        let mut transport = FaultyTransport::new(MockBus::new().transport())
+            .schedule(0, Fault::Delay(Duration::from_millis(20)));
+        transport.set_nonblocking(true).unwrap();
+        transport.write_all(CALL).unwrap();
+
+        let mut buf = [0; 256];
+        let error = transport.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::WouldBlock);
+
+        sleep(Duration::from_millis(30));
+        assert!(transport.read(&mut buf).unwrap() > 0);
+    }
+
+    #[test]
+    fn truncate_always_cuts_the_frame_off() {
+        let mut transport = FaultyTransport::new(MockBus::new().transport())
+            .schedule(0, Fault::Truncate(usize::MAX));
+        transport.write_all(CALL).unwrap();
+
+        let mut buf = [0; 4096];
+        let len = transport.read(&mut buf).unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&buf[..len]);
+
+        assert_eq!(decoder.next_unbounded_frame(), None);
+        assert!(serde_json::from_slice::<serde_json::Value>(&decoder.pending()[1..]).is_err());
+    }
+}
@@ -1,7 +1,22 @@
+#[cfg(feature = "async")]
+pub mod async_bus;
 pub mod bus;
 pub mod call;
 pub mod device;
+pub mod discovery;
 pub mod error;
+#[cfg(feature = "mock")]
+pub mod fault;
+pub mod middleware;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod mux;
 pub mod prelude;
+pub mod rate_limit;
 pub mod response;
+pub mod retry;
+pub mod stats;
+mod trace;
+pub mod transcript;
+pub mod transport;
 pub mod types;
@@ -0,0 +1,221 @@
+//! Middleware which every call made on a [`DeviceBus`] or [`SyncDeviceBus`] passes through.
+//!
+//! Middleware is added to a bus with [`DeviceBus::add_middleware`], and sees each call as a
+//! [`Request`] before it is written and the data of its response after it is read. It can inspect
+//! or change either of them, make the call more than once, or answer it without making it at all.
+//! Since devices make their calls through the bus they were found on, this applies to them as well:
+//!
+//! ```no_run
+//! # use oc2_hlapi::prelude::*;
+//! # use oc2_hlapi::middleware;
+//! # fn main() -> oc2_hlapi::error::Result<()> {
+//! let bus = DeviceBus::new("/dev/hvc0")?;
+//!
+//! bus.add_middleware(middleware::from_fn(|request, mut next| {
+//!     let result = next.run(request);
+//!     eprintln!("{}: {:?}", request.message, result);
+//!     result
+//! }));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only calls made with `call` and its variants pass through middleware. The lower-level methods
+//! for sending and receiving single messages, such as [`DeviceBus::write_message`], bypass it.
+//!
+//! Middleware runs while the bus is locked for the call passing through it. It must not make calls
+//! on the same bus, which panics on a [`DeviceBus`] and deadlocks a [`SyncDeviceBus`], and
+//! anything it waits for holds up the calls of every other thread using the bus. Retrying and
+//! throttling calls is better left to a [`RetryPolicy`] and a [`RateLimiter`], which wait without
+//! holding the bus.
+//!
+//! [`DeviceBus`]: crate::DeviceBus
+//! [`DeviceBus::add_middleware`]: crate::DeviceBus::add_middleware
+//! [`DeviceBus::write_message`]: crate::DeviceBus::write_message
+//! [`RateLimiter`]: crate::rate_limit::RateLimiter
+//! [`RetryPolicy`]: crate::retry::RetryPolicy
+//! [`SyncDeviceBus`]: crate::SyncDeviceBus
+
+use crate::call::{ApiCall, Call};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A layer around the calls made on a bus.
+pub trait Middleware: Send {
+    /// Handles a call, usually by passing it on to the rest of the chain with [`Next::run`]. On
+    /// success, this returns the `data` field of the response, or [`Value::Null`] if the response
+    /// didn't have one.
+    ///
+    /// This is called with the bus locked, so it must not make calls on the same bus. See the
+    /// [module documentation](self) for details.
+    fn call(&mut self, request: &Request<'_>, next: Next<'_>) -> Result<Value>;
+}
+
+/// A call passing through middleware.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Request<'a> {
+    /// The `type` field of the call.
+    pub kind: &'static str,
+    /// The ID of the device the call is made on, if it is made on one.
+    pub device_id: Option<uuid::Uuid>,
+    /// The name of the method invoked by the call, if it invokes one.
+    pub method_name: Option<&'a str>,
+    /// The call as it is written to the bus. Changing this doesn't change the other fields.
+    pub message: Value,
+}
+
+impl<'a> Request<'a> {
+    /// Creates the request for a call.
+    pub fn new<T: ApiCall + Serialize>(call: &'a Call<T>) -> Result<Self> {
+        Ok(Self {
+            kind: call.kind(),
+            device_id: call.device_id(),
+            method_name: call.method_name(),
+            message: serde_json::to_value(call)?,
+        })
+    }
+}
+
+/// The rest of a middleware chain, ending with the bus itself.
+pub struct Next<'a> {
+    chain: &'a mut [Box<dyn Middleware>],
+    endpoint: &'a mut dyn FnMut(&Request<'_>) -> Result<Value>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        chain: &'a mut [Box<dyn Middleware>],
+        endpoint: &'a mut dyn FnMut(&Request<'_>) -> Result<Value>,
+    ) -> Self {
+        Self { chain, endpoint }
+    }
+
+    /// Passes a call on to the rest of the chain. This can be done more than once, or not at all.
+    pub fn run(&mut self, request: &Request<'_>) -> Result<Value> {
+        match self.chain.split_first_mut() {
+            Some((middleware, chain)) => middleware.call(request, Next::new(chain, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// Middleware made from a closure, created by [`from_fn`].
+#[derive(Clone, Debug)]
+pub struct FromFn<F>(F);
+
+impl<F> Middleware for FromFn<F>
+where
+    F: FnMut(&Request<'_>, Next<'_>) -> Result<Value> + Send,
+{
+    fn call(&mut self, request: &Request<'_>, next: Next<'_>) -> Result<Value> {
+        (self.0)(request, next)
+    }
+}
+
+/// Creates middleware from a closure with the same signature as [`Middleware::call`].
+pub fn from_fn<F>(f: F) -> FromFn<F>
+where
+    F: FnMut(&Request<'_>, Next<'_>) -> Result<Value> + Send,
+{
+    FromFn(f)
+}
+
+/// A response of any kind, which is how the bus answers requests at the end of the chain.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type", content = "data")]
+enum RawResponse {
+    #[serde(alias = "list", alias = "methods", rename = "result")]
+    Response(Option<Value>),
+    Error(String),
+}
+
+/// Deserializes the data of a response from a message without its delimiters.
+pub(crate) fn decode_raw(message: &[u8]) -> Result<Value> {
+    match serde_json::from_slice(message)? {
+        RawResponse::Response(data) => Ok(data.unwrap_or(Value::Null)),
+        RawResponse::Error(e) => Err(Error::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "mock")]
+    use crate::call::Invoke;
+    #[cfg(feature = "mock")]
+    use crate::mock::MockBus;
+    #[cfg(feature = "mock")]
+    use crate::response::{self, Return};
+    #[cfg(feature = "mock")]
+    use crate::types::DeviceDescriptor;
+    #[cfg(feature = "mock")]
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn middleware_runs_in_order_added() {
+        let mock = MockBus::new();
+        let bus = mock.bus();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        for name in ["outer", "inner"] {
+            let log = Arc::clone(&log);
+
+            bus.add_middleware(from_fn(move |request, mut next| {
+                log.lock().unwrap().push(format!("{name} before"));
+                let result = next.run(request);
+                log.lock().unwrap().push(format!("{name} after"));
+                result
+            }));
+        }
+
+        bus.call(Call::list()).unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["outer before", "inner before", "inner after", "outer after"]
+        );
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn middleware_can_answer_without_calling_next() {
+        let mock = MockBus::new();
+        let id = uuid::Uuid::new_v4();
+        mock.add_device(
+            DeviceDescriptor {
+                device_id: id,
+                type_names: Box::new(["test".into()]),
+            },
+            [],
+        );
+        mock.returns(id, "getEnergyStored", 10).unwrap();
+
+        let bus = mock.bus();
+        let reached = Arc::new(Mutex::new(false));
+        let inner_reached = Arc::clone(&reached);
+
+        bus.add_middleware(from_fn(|request, mut next| match request.method_name {
+            Some("getEnergyStored") => Ok(Value::from(42)),
+            Some(_) => next.run(request),
+            None => Ok(Value::Array(Vec::new())),
+        }));
+        bus.add_middleware(from_fn(move |request, mut next| {
+            *inner_reached.lock().unwrap() = true;
+            next.run(request)
+        }));
+
+        let response::List(list) = bus.call(Call::list()).unwrap();
+        assert!(list.is_empty());
+
+        let energy = bus.call(Call::<Invoke<'_, i32>>::invoke(id, "getEnergyStored", &[]));
+        assert!(matches!(energy, Ok(Return(42))));
+
+        // Neither call went past the first middleware.
+        assert!(!*reached.lock().unwrap());
+        assert!(mock.calls().is_empty());
+    }
+}
@@ -0,0 +1,552 @@
+//! An in-process stand-in for the HLAPI, for testing device code without a running OC2 VM.
+//!
+//! A [`MockBus`] can be connected to a bus directly with [`MockBus::bus`], or on Linux, through a
+//! pseudo-terminal with [`MockBus::tty`] to test the bus's handling of a real console as well.
+
+use crate::bus::{encode_message, DeviceBus, FrameDecoder, SyncDeviceBus};
+use crate::call::{self, ApiCall};
+use crate::error::Result;
+use crate::response::{self, Response, Return};
+use crate::transport::Transport;
+use crate::types::{DeviceDescriptor, MethodDescriptor};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::io::{self, Read, Write};
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use uuid::Uuid;
+
+#[cfg(target_os = "linux")]
+use std::ffi::{CStr, OsStr};
+#[cfg(target_os = "linux")]
+use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use std::thread::{self, JoinHandle};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+type Handler = Box<dyn FnMut(&[Value]) -> StdResult<Value, String> + Send>;
+
+/// A call received by a [`MockBus`].
+#[derive(Clone, PartialEq, Debug)]
+pub enum MockCall {
+    /// A request for the list of devices.
+    List,
+    /// A request for the methods of the device with the given ID.
+    Methods(Uuid),
+    /// A method invocation.
+    Invoke {
+        /// The ID of the device the method was invoked on.
+        device_id: Uuid,
+        /// The name of the invoked method.
+        name: Box<str>,
+        /// The parameters the method was invoked with.
+        parameters: Box<[Value]>,
+    },
+}
+
+/// A scripted table of devices which answers `list`, `methods` and `invoke` calls the same way the
+/// HLAPI does. Every call received is recorded, so that tests can assert on the sequence of calls
+/// made by the code under test.
+///
+/// Cloning a `MockBus` yields a handle to the same device table.
+#[derive(Clone, Default)]
+pub struct MockBus(Arc<Mutex<State>>);
+
+impl MockBus {
+    /// Creates a new mock bus with no devices.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a device to the bus, along with the methods it reports. Methods only answer invocations
+    /// once a handler or return value has been installed for them.
+    pub fn add_device<I>(&self, descriptor: DeviceDescriptor, methods: I)
+    where
+        I: IntoIterator<Item = MethodDescriptor>,
+    {
+        self.state().devices.push(MockDevice {
+            descriptor,
+            methods: methods.into_iter().collect(),
+            handlers: HashMap::new(),
+        });
+    }
+
+    /// Installs a handler which is called with the parameters of every invocation of the given
+    /// method. An `Err` returned from the handler is sent back as an HLAPI error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no device with the given ID has been added.
+    pub fn on_invoke<F>(&self, device_id: Uuid, name: &str, handler: F)
+    where
+        F: FnMut(&[Value]) -> StdResult<Value, String> + Send + 'static,
+    {
+        self.state()
+            .device_mut(device_id)
+            .expect("device has not been added to the mock bus")
+            .handlers
+            .insert(name.into(), Box::new(handler));
+    }
+
+    /// Makes every invocation of the given method return the given value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no device with the given ID has been added.
+    pub fn returns<R: Serialize>(&self, device_id: Uuid, name: &str, value: R) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+
+        self.on_invoke(device_id, name, move |_| Ok(value.clone()));
+
+        Ok(())
+    }
+
+    /// Makes every invocation of the given method fail with the given error message.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no device with the given ID has been added.
+    pub fn fails(&self, device_id: Uuid, name: &str, message: &str) {
+        let message = message.to_owned();
+
+        self.on_invoke(device_id, name, move |_| Err(message.clone()));
+    }
+
+    /// Returns every call received so far, in the order they were received.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state().calls.clone()
+    }
+
+    /// Forgets every call received so far.
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    /// Creates a new transport connected to this mock bus.
+    pub fn transport(&self) -> MockTransport {
+        MockTransport {
+            bus: self.clone(),
+            input: FrameDecoder::new(),
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Creates a new device bus connected to this mock bus.
+    pub fn bus(&self) -> DeviceBus {
+        DeviceBus::with_transport(self.transport())
+    }
+
+    /// Creates a new shared device bus connected to this mock bus.
+    pub fn sync_bus(&self) -> SyncDeviceBus {
+        SyncDeviceBus::with_transport(self.transport())
+    }
+
+    /// Opens a pseudo-terminal which answers the messages written to it using this mock bus. See
+    /// [`MockTty`] for details.
+    #[cfg(target_os = "linux")]
+    pub fn tty(&self) -> Result<MockTty> {
+        MockTty::open(self.transport())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panicking handler should not take every later assertion down with it.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn answer(&self, message: &[u8]) -> Vec<u8> {
+        let call = match serde_json::from_slice::<IncomingCall>(message) {
+            Ok(call) => call,
+            Err(e) => return error_response(format!("invalid message: {e}")),
+        };
+
+        let mut state = self.state();
+
+        match call {
+            IncomingCall::List => {
+                state.calls.push(MockCall::List);
+
+                let list = state
+                    .devices
+                    .iter()
+                    .map(|device| device.descriptor.clone())
+                    .collect();
+
+                to_frame(&Response::<call::List>::Response(response::List(list)))
+            }
+            IncomingCall::Methods(device_id) => {
+                state.calls.push(MockCall::Methods(device_id));
+
+                match state.device_mut(device_id) {
+                    Some(device) => {
+                        let methods = response::Methods(device.methods.clone().into());
+                        to_frame(&Response::<call::Methods>::Response(methods))
+                    }
+                    None => error_response(format!("unknown device: {device_id}")),
+                }
+            }
+            IncomingCall::Invoke {
+                device_id,
+                name,
+                parameters,
+            } => {
+                state.calls.push(MockCall::Invoke {
+                    device_id,
+                    name: name.as_str().into(),
+                    parameters: parameters.as_slice().into(),
+                });
+
+                let Some(device) = state.device_mut(device_id) else {
+                    return error_response(format!("unknown device: {device_id}"));
+                };
+
+                let Some(handler) = device.handlers.get_mut(name.as_str()) else {
+                    return error_response(format!("unknown method: {name}"));
+                };
+
+                match handler(&parameters) {
+                    Ok(value) => to_frame(&Response::<InvokeValue>::Response(Return(value))),
+                    Err(e) => error_response(e),
+                }
+            }
+        }
+    }
+}
+
+impl Debug for MockBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+
+        f.debug_struct("MockBus")
+            .field("devices", &state.devices)
+            .field("calls", &state.calls)
+            .finish()
+    }
+}
+
+/// A transport which answers every message written to it using a [`MockBus`].
+#[derive(Debug)]
+pub struct MockTransport {
+    bus: MockBus,
+    input: FrameDecoder,
+    output: VecDeque<u8>,
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.output.read(buf)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.input.push(buf);
+
+        while let Some(message) = self.input.next_unbounded_frame() {
+            self.output.extend(self.bus.answer(message));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MockTransport {}
+
+/// How long the peer of a [`MockTty`] waits for a message before checking whether it should stop.
+#[cfg(target_os = "linux")]
+const PEER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A pseudo-terminal whose master side is answered by a [`MockBus`] on a background thread.
+///
+/// The slave side at [`path`](Self::path) is a real terminal, so a bus opened on it with
+/// [`DeviceBus::new`] goes through the same raw mode setup, polling and framing as it does on the
+/// console of an OC2 VM:
+///
+/// ```
+/// # use oc2_hlapi::prelude::*;
+/// # use oc2_hlapi::mock::MockBus;
+/// # fn main() -> oc2_hlapi::error::Result<()> {
+/// let mock = MockBus::new();
+/// let id = uuid::Uuid::new_v4();
+///
+/// mock.add_device(
+///     DeviceDescriptor {
+///         device_id: id,
+///         type_names: Box::new(["redstone".into()]),
+///     },
+///     [],
+/// );
+/// mock.returns(id, "getRedstoneInput", 15)?;
+///
+/// let tty = mock.tty()?;
+/// let bus = DeviceBus::new(tty.path())?;
+///
+/// // Noise on the console and responses which arrive a few bytes at a time are skipped and
+/// // reassembled by the bus.
+/// tty.inject(b"login: ")?;
+/// tty.split_responses(7);
+///
+/// let redstone = bus.find::<RedstoneDevice>()?.unwrap();
+/// assert_eq!(redstone.get_redstone_input(Direction::Front)?, 15);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Dropping the terminal stops its peer and closes the master side, after which any bus still open
+/// on it fails to read.
+#[cfg(target_os = "linux")]
+pub struct MockTty {
+    path: PathBuf,
+    master: Arc<File>,
+    peer: Arc<Peer>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+struct Peer {
+    stop: AtomicBool,
+    chunk_size: AtomicUsize,
+    delay_micros: AtomicU64,
+}
+
+#[cfg(target_os = "linux")]
+impl MockTty {
+    fn open(mut transport: MockTransport) -> Result<Self> {
+        // SAFETY: `posix_openpt` returns either a new file descriptor or -1, and the one returned
+        // is owned by the file from then on.
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC);
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            File::from_raw_fd(fd)
+        };
+
+        let fd = master.as_raw_fd();
+        let mut name = [0; 128];
+
+        // SAFETY: The file descriptor is a valid master, and the buffer is as long as it is said
+        // to be. `ptsname_r` always terminates the name it writes.
+        let path = unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            match libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) {
+                0 => PathBuf::from(OsStr::from_bytes(CStr::from_ptr(name.as_ptr()).to_bytes())),
+                e => return Err(io::Error::from_raw_os_error(e).into()),
+            }
+        };
+
+        let master = Arc::new(master);
+        let peer = Arc::new(Peer::default());
+
+        let thread = {
+            let master = Arc::clone(&master);
+            let peer = Arc::clone(&peer);
+
+            thread::Builder::new()
+                .name("mock-tty".into())
+                .spawn(move || peer.run(&master, &mut transport))?
+        };
+
+        Ok(Self {
+            path,
+            master,
+            peer,
+            thread: Some(thread),
+        })
+    }
+
+    /// The path of the slave side of the terminal, which a bus can be opened on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes raw bytes to the terminal, such as garbage or a partial message. These can end up in
+    /// the middle of a response which is being written at the same time.
+    pub fn inject(&self, bytes: &[u8]) -> Result<()> {
+        (&*self.master).write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Writes every later response in pieces of at most the given number of bytes, pausing
+    /// briefly after each one so that they are read separately. Zero writes responses whole, which
+    /// is the default.
+    pub fn split_responses(&self, chunk_size: usize) {
+        self.peer.chunk_size.store(chunk_size, Ordering::Relaxed);
+    }
+
+    /// Waits for the given time before writing every later response.
+    pub fn delay_responses(&self, delay: Duration) {
+        let micros = u64::try_from(delay.as_micros()).unwrap_or(u64::MAX);
+        self.peer.delay_micros.store(micros, Ordering::Relaxed);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Debug for MockTty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockTty")
+            .field("path", &self.path)
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for MockTty {
+    fn drop(&mut self) {
+        self.peer.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            // A panic in the peer means a handler panicked, which the calls made by the bus have
+            // already failed for.
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Peer {
+    /// Answers the messages read from the master side until the terminal is dropped.
+    fn run(&self, mut master: &File, transport: &mut MockTransport) {
+        let mut buf = [0; 4096];
+        let mut response = Vec::new();
+
+        while !self.stop.load(Ordering::Relaxed) {
+            let mut poll_fd = libc::pollfd {
+                fd: master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+
+            // SAFETY: The file descriptor stays open for as long as `master` is borrowed.
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, PEER_POLL_INTERVAL.as_millis() as _) };
+
+            if ready <= 0 || poll_fd.revents & libc::POLLIN == 0 {
+                // The master side hangs up whenever no bus has the slave side open, which it
+                // reports right away instead of waiting for the timeout.
+                if poll_fd.revents & libc::POLLHUP != 0 {
+                    thread::sleep(PEER_POLL_INTERVAL);
+                }
+
+                continue;
+            }
+
+            let read = match master.read(&mut buf) {
+                Ok(read) => read,
+                Err(_) => continue,
+            };
+
+            // Answering a message can only fail by panicking in a handler.
+            let _ = transport.write(&buf[..read]);
+
+            response.clear();
+            let _ = transport.read_to_end(&mut response);
+
+            if response.is_empty() {
+                continue;
+            }
+
+            let delay = self.delay_micros.load(Ordering::Relaxed);
+            if delay > 0 {
+                thread::sleep(Duration::from_micros(delay));
+            }
+
+            let chunk_size = match self.chunk_size.load(Ordering::Relaxed) {
+                0 => response.len(),
+                chunk_size => chunk_size,
+            };
+
+            for chunk in response.chunks(chunk_size) {
+                if master.write_all(chunk).is_err() {
+                    break;
+                }
+
+                if chunk_size < response.len() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    devices: Vec<MockDevice>,
+    calls: Vec<MockCall>,
+}
+
+impl State {
+    fn device_mut(&mut self, device_id: Uuid) -> Option<&mut MockDevice> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.descriptor.device_id == device_id)
+    }
+}
+
+struct MockDevice {
+    descriptor: DeviceDescriptor,
+    methods: Vec<MethodDescriptor>,
+    handlers: HashMap<Box<str>, Handler>,
+}
+
+impl Debug for MockDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockDevice")
+            .field("descriptor", &self.descriptor)
+            .field("methods", &self.methods)
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+type InvokeValue = call::Invoke<'static, Value>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type", content = "data")]
+enum IncomingCall {
+    List,
+    Methods(Uuid),
+    #[serde(rename_all = "camelCase")]
+    Invoke {
+        device_id: Uuid,
+        name: String,
+        parameters: Vec<Value>,
+    },
+}
+
+fn to_frame<T: ApiCall>(response: &Response<T>) -> Vec<u8>
+where
+    T::Response: Serialize,
+{
+    let mut frame = Vec::new();
+
+    // Handlers can return values of any size, which a test may want to check the bus's own limit
+    // with. Every response here is made of JSON values and descriptors, which always serialize.
+    encode_message(&mut frame, response, usize::MAX).expect("mock response failed to serialize");
+
+    frame
+}
+
+fn error_response(message: String) -> Vec<u8> {
+    to_frame(&Response::<InvokeValue>::Error(message))
+}
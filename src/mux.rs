@@ -0,0 +1,860 @@
+//! A multiplexer which lets several processes share one device bus.
+//!
+//! Only one process can use the HLAPI console at a time, since two processes reading from it steal
+//! each other's responses. A [`Multiplexer`] owns the console instead, and serves any number of
+//! clients over a Unix socket. Clients connect with
+//! [`DeviceBus::connect`](crate::DeviceBus::connect) and are otherwise used exactly like a bus
+//! opened on the console itself.
+//!
+//! A multiplexer can also serve clients over TCP, which lets device code run on another machine
+//! against the devices of a running VM. TCP clients have to present a shared secret before any of
+//! their calls are forwarded, and connect with
+//! [`DeviceBus::connect_tcp`](crate::DeviceBus::connect_tcp). The secret is sent in the clear, so
+//! this should only be exposed on a trusted network or through a tunnel.
+
+use crate::bus::{encode_message, encode_raw_message, FrameDecoder, MAX_MESSAGE_SIZE};
+use crate::call::{self, ApiCall};
+use crate::error::{Error, MessageKind, Result};
+use crate::response::Response;
+use crate::trace;
+use crate::transport::Transport;
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Registry, Token};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::fs;
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream, ToSocketAddrs};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The path of the socket which the multiplexer listens on by default.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/oc2-hlapi.sock";
+
+const LISTENER: Token = Token(0);
+const BUS: Token = Token(1);
+
+// The `type` of the first message sent by a TCP client, and of the reply which accepts it.
+const HELLO: &str = "hello";
+
+// The longest message a client may send before it has presented the secret, so that anyone who can
+// reach the multiplexer can't fill up the memory of the VM.
+const HELLO_MAX_LEN: usize = 1024;
+
+// How long a client has to present the secret before it is disconnected.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server which forwards the calls of every connected client to one device bus.
+///
+/// Calls are written to the bus in the order they are received, and each response is sent back to
+/// the client which made the call, so clients never see each other's responses. Responses to a
+/// client which disconnected before they arrived are thrown away.
+pub struct Multiplexer {
+    poll: Poll,
+    listener: Listener,
+    // The path of the Unix socket, which is removed when the multiplexer is dropped.
+    socket_path: Option<PathBuf>,
+    secret: Option<Box<str>>,
+    bus: Box<dyn Transport>,
+    bus_fd: Option<RawFd>,
+    decoder: FrameDecoder,
+    write_buffer: Vec<u8>,
+    // The calls which have been sent to the bus but haven't been answered yet, oldest first.
+    in_flight: VecDeque<InFlight>,
+    clients: HashMap<Token, Client>,
+    next_token: usize,
+    list_ttl: Option<Duration>,
+    list_cache: Option<(Instant, Vec<u8>)>,
+    max_message_size: usize,
+}
+
+impl Multiplexer {
+    /// Creates a multiplexer which serves the given bus on a Unix socket at the specified path.
+    ///
+    /// A socket file left behind by a multiplexer which is no longer running is replaced. The
+    /// socket file is removed again when the multiplexer is dropped.
+    pub fn bind<P, T>(socket_path: P, bus: T) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        T: Transport + 'static,
+    {
+        let socket_path = socket_path.as_ref();
+
+        if socket_path.exists() && StdUnixStream::connect(socket_path).is_err() {
+            fs::remove_file(socket_path)?;
+        }
+
+        let listener = Listener::Unix(UnixListener::bind(socket_path)?);
+        let mut multiplexer = Self::new(listener, None, Box::new(bus))?;
+        multiplexer.socket_path = Some(socket_path.to_owned());
+
+        Ok(multiplexer)
+    }
+
+    /// Creates a multiplexer which serves the given bus over TCP at the specified address. Clients
+    /// have to present the given secret before they can make any calls.
+    pub fn bind_tcp<A, T>(addr: A, secret: &str, bus: T) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+        T: Transport + 'static,
+    {
+        let listener = StdTcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let listener = Listener::Tcp(TcpListener::from_std(listener));
+
+        Self::new(listener, Some(secret.into()), Box::new(bus))
+    }
+
+    fn new(
+        mut listener: Listener,
+        secret: Option<Box<str>>,
+        mut bus: Box<dyn Transport>,
+    ) -> Result<Self> {
+        let poll = Poll::new()?;
+        let bus_fd = bus.raw_fd();
+
+        bus.set_nonblocking(true)?;
+
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        if let Some(fd) = bus_fd {
+            poll.registry().register(
+                &mut SourceFd(&fd),
+                BUS,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+        }
+
+        Ok(Self {
+            poll,
+            listener,
+            socket_path: None,
+            secret,
+            bus,
+            bus_fd,
+            decoder: FrameDecoder::new(),
+            write_buffer: Vec::new(),
+            in_flight: VecDeque::new(),
+            clients: HashMap::new(),
+            next_token: BUS.0 + 1,
+            list_ttl: None,
+            list_cache: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+        })
+    }
+
+    /// Answers `list` calls from a cached response for the given duration after the bus last
+    /// answered one, instead of forwarding every one of them to the bus. By default, nothing is
+    /// cached.
+    pub fn cache_list(mut self, ttl: Duration) -> Self {
+        self.list_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the maximum size in bytes of a call sent by a client. Longer calls are answered with an
+    /// error instead of being forwarded. This should be at least as large as the limit of the
+    /// clients' buses. Defaults to [`MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Serves clients until the bus fails or is closed.
+    ///
+    /// Transports which aren't backed by a file descriptor, such as in-memory buffers, are read
+    /// from after every write while calls are waiting for a response.
+    pub fn run(&mut self) -> Result<()> {
+        let mut events = Events::with_capacity(64);
+
+        loop {
+            let timeout = self
+                .clients
+                .values()
+                .filter(|client| !client.authenticated)
+                .map(|client| {
+                    (client.connected + HELLO_TIMEOUT).saturating_duration_since(Instant::now())
+                })
+                .min();
+
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == IoErrorKind::Interrupted {
+                    continue;
+                }
+
+                return Err(e.into());
+            }
+
+            for event in &events {
+                match event.token() {
+                    LISTENER => self.accept()?,
+                    // The bus is serviced below, whether or not it was the bus which woke us up.
+                    BUS => {}
+                    token => self.read_client(token),
+                }
+            }
+
+            self.expire_hellos();
+            self.service_bus()?;
+
+            let tokens = self.clients.keys().copied().collect::<Vec<_>>();
+
+            for token in tokens {
+                self.write_client(token);
+            }
+        }
+    }
+
+    fn accept(&mut self) -> Result<()> {
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == IoErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+
+            let authenticated = self.secret.is_none();
+            let max_len = if authenticated {
+                self.max_message_size
+            } else {
+                HELLO_MAX_LEN
+            };
+
+            self.clients.insert(
+                token,
+                Client {
+                    stream,
+                    decoder: FrameDecoder::with_max_len(max_len),
+                    connected: Instant::now(),
+                    authenticated,
+                    closing: false,
+                    replies: VecDeque::new(),
+                    output: Vec::new(),
+                },
+            );
+        }
+    }
+
+    fn read_client(&mut self, token: Token) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+
+        let mut closed = loop {
+            match client.decoder.read_from(&mut client.stream) {
+                Ok(0) => break true,
+                Ok(_) => {}
+                Err(Error::Io(e)) if e.kind() == IoErrorKind::WouldBlock => break false,
+                Err(Error::Io(e)) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(_) => break true,
+            }
+        };
+
+        loop {
+            let message = match client.decoder.next_frame() {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(Error::MessageLengthExceeded { length, max, .. }) if client.authenticated => {
+                    let error = Error::MessageLengthExceeded {
+                        kind: MessageKind::Call,
+                        length,
+                        max,
+                    };
+
+                    client
+                        .replies
+                        .push_back(Some(error_frame(error.to_string())));
+                    continue;
+                }
+                // A hello longer than any secret is either a broken client or one trying to use up
+                // the memory of the VM, and the connection is dropped either way.
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            };
+
+            if client.closing {
+                break;
+            }
+
+            if !client.authenticated {
+                let accepted = serde_json::from_slice::<Hello>(message).is_ok_and(|hello| {
+                    hello.kind == HELLO
+                        && self
+                            .secret
+                            .as_deref()
+                            .zip(hello.data.as_deref())
+                            .is_some_and(|(secret, data)| {
+                                constant_time_eq(secret.as_bytes(), data.as_bytes())
+                            })
+                });
+
+                let reply = if accepted {
+                    client.authenticated = true;
+
+                    // Calls can be as long as the multiplexer allows from now on.
+                    let pending = client.decoder.pending().to_vec();
+                    client.decoder = FrameDecoder::with_max_len(self.max_message_size);
+                    client.decoder.push(&pending);
+
+                    hello_frame(None)
+                } else {
+                    client.closing = true;
+                    error_frame(String::from("authentication failed"))
+                };
+
+                client.replies.push_back(Some(reply));
+                continue;
+            }
+
+            let kind = serde_json::from_slice::<Header>(message).map(|header| header.kind);
+
+            let reply = match kind.as_deref() {
+                Err(e) => Some(error_frame(format!("invalid message: {e}"))),
+                Ok(call::List::KIND) => match &self.list_cache {
+                    Some((time, list)) if self.list_ttl.is_some_and(|ttl| time.elapsed() < ttl) => {
+                        Some(list.clone())
+                    }
+                    _ => None,
+                },
+                Ok(_) => None,
+            };
+
+            if reply.is_none() {
+                encode_raw_message(&mut self.write_buffer, message);
+
+                self.in_flight.push_back(InFlight {
+                    client: token,
+                    list: kind.as_deref().is_ok_and(|kind| kind == call::List::KIND),
+                });
+            }
+
+            client.replies.push_back(reply);
+        }
+
+        // There's no need to wait for the end of a hello which is already too long.
+        if closed || (!client.authenticated && client.decoder.is_discarding()) {
+            self.disconnect(token);
+        }
+    }
+
+    fn write_client(&mut self, token: Token) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+
+        // Replies are sent in the same order as the calls they answer, so a reply which is ready
+        // has to wait for any calls before it which are still waiting for the bus.
+        while let Some(Some(reply)) = client.replies.front() {
+            client.output.extend_from_slice(reply);
+            client.replies.pop_front();
+        }
+
+        while !client.output.is_empty() {
+            match client.stream.write(&client.output) {
+                Ok(0) => return self.disconnect(token),
+                Ok(n) => {
+                    client.output.drain(..n);
+                }
+                Err(e) if e.kind() == IoErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(_) => return self.disconnect(token),
+            }
+        }
+
+        if client.closing && client.replies.is_empty() {
+            self.disconnect(token);
+        }
+    }
+
+    /// Disconnects every client which hasn't presented the secret in time.
+    fn expire_hellos(&mut self) {
+        let expired = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                !client.authenticated && client.connected.elapsed() >= HELLO_TIMEOUT
+            })
+            .map(|(&token, _)| token)
+            .collect::<Vec<_>>();
+
+        for token in expired {
+            self.disconnect(token);
+        }
+    }
+
+    fn disconnect(&mut self, token: Token) {
+        if let Some(mut client) = self.clients.remove(&token) {
+            // The client is gone either way, so there's nothing to be done about a failure here.
+            let _ = self.poll.registry().deregister(&mut client.stream);
+        }
+    }
+
+    fn service_bus(&mut self) -> Result<()> {
+        while !self.write_buffer.is_empty() {
+            match self.bus.write(&self.write_buffer) {
+                Ok(0) => return Err(Error::Io(IoErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                }
+                Err(e) if e.kind() == IoErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.bus.flush()?;
+
+        while self.bus_fd.is_some() || !self.in_flight.is_empty() {
+            match self.decoder.read_from(&mut *self.bus) {
+                Ok(0) => return Err(Error::ReadZero),
+                Ok(_) => {}
+                Err(Error::Io(e)) if e.kind() == IoErrorKind::WouldBlock => break,
+                Err(Error::Io(e)) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+
+            loop {
+                // A damaged response still answers the call at the front of the queue. Skipping it
+                // would send every later response to the client of the call before it.
+                let message = match self.decoder.next_valid_frame(trace::garbage) {
+                    Ok(Some(message)) => Ok(message),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+
+                // Anything the bus sends without being asked is thrown away.
+                let Some(in_flight) = self.in_flight.pop_front() else {
+                    continue;
+                };
+
+                let reply = match message {
+                    Ok(message) => {
+                        let mut reply = Vec::new();
+                        encode_raw_message(&mut reply, message);
+
+                        let is_list = serde_json::from_slice::<Header>(message)
+                            .is_ok_and(|header| header.kind == call::List::RESPONSE_KIND);
+
+                        if in_flight.list && is_list && self.list_ttl.is_some() {
+                            self.list_cache = Some((Instant::now(), reply.clone()));
+                        }
+
+                        reply
+                    }
+                    Err(e) => error_frame(format!("invalid response: {e}")),
+                };
+
+                if let Some(client) = self.clients.get_mut(&in_flight.client) {
+                    if let Some(slot) = client.replies.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(reply);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        if let Some(socket_path) = &self.socket_path {
+            let _ = fs::remove_file(socket_path);
+        }
+    }
+}
+
+impl Debug for Multiplexer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multiplexer")
+            .field("socket_path", &self.socket_path)
+            .field("clients", &self.clients.len())
+            .field("in_flight", &self.in_flight.len())
+            .field("list_ttl", &self.list_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+struct InFlight {
+    client: Token,
+    // Whether the call was a `list` call, whose response can be cached.
+    list: bool,
+}
+
+struct Client {
+    stream: Stream,
+    decoder: FrameDecoder,
+    connected: Instant,
+    // Whether the client has presented the secret, or doesn't need to.
+    authenticated: bool,
+    // Whether the client is disconnected once its replies have been sent.
+    closing: bool,
+    // A reply for every call received from the client, in order. Calls which were forwarded to the
+    // bus have no reply until the bus answers them.
+    replies: VecDeque<Option<Vec<u8>>>,
+    output: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Header<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: Cow<'a, str>,
+}
+
+/// The first message sent by a TCP client, and the reply which accepts it.
+#[derive(Serialize, Deserialize)]
+struct Hello<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: Cow<'a, str>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    data: Option<Cow<'a, str>>,
+}
+
+fn hello_frame(secret: Option<&str>) -> Vec<u8> {
+    let hello = Hello {
+        kind: Cow::Borrowed(HELLO),
+        data: secret.map(Cow::Borrowed),
+    };
+
+    let mut frame = Vec::new();
+
+    // A hello is only ever written by this module, so it isn't held to the bus's size limit, and
+    // a struct of strings always serializes.
+    encode_message(&mut frame, &hello, usize::MAX).expect("hello message failed to serialize");
+
+    frame
+}
+
+/// Compares two byte strings in an amount of time which only depends on their lengths, so that
+/// the secret can't be guessed one byte at a time by timing failed attempts.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn error_frame(message: String) -> Vec<u8> {
+    let mut frame = Vec::new();
+    let response = Response::<call::Invoke<'static, Value>>::Error(message);
+
+    // Errors can quote parts of a client's call, so they are not limited either; the response
+    // only holds the message string, which always serializes.
+    encode_message(&mut frame, &response, usize::MAX).expect("error response failed to serialize");
+
+    frame
+}
+
+/// Connects to a multiplexer serving a bus over TCP and presents the secret to it.
+pub(crate) fn connect_tcp<A: ToSocketAddrs>(
+    addr: A,
+    secret: &str,
+    timeout: Option<Duration>,
+) -> Result<StdTcpStream> {
+    let mut stream = StdTcpStream::connect(addr)?;
+
+    // Every call is a small write which waits for its response, so batching them up only adds
+    // latency.
+    stream.set_nodelay(true)?;
+    stream.write_all(&hello_frame(Some(secret)))?;
+    stream.set_read_timeout(timeout)?;
+
+    let mut decoder = FrameDecoder::new();
+
+    loop {
+        if let Some(message) = decoder.next_frame()? {
+            if serde_json::from_slice::<Hello>(message).is_ok_and(|hello| hello.kind == HELLO) {
+                break;
+            }
+
+            let message = match serde_json::from_slice(message) {
+                Ok(Response::<call::Invoke<'static, Value>>::Error(e)) => e,
+                _ => String::from("unexpected reply from the bridge"),
+            };
+
+            return Err(Error::Handshake(message.into()));
+        }
+
+        match decoder.read_from(&mut stream) {
+            Ok(0) => return Err(Error::ReadZero),
+            Ok(_) => {}
+            Err(Error::Io(e))
+                if matches!(e.kind(), IoErrorKind::TimedOut | IoErrorKind::WouldBlock) =>
+            {
+                return Err(Error::Timeout);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    stream.set_read_timeout(None)?;
+
+    Ok(stream)
+}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+
+                Ok(Stream::Tcp(stream))
+            }
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Self::Unix(listener) => listener.register(registry, token, interests),
+            Self::Tcp(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Self::Unix(listener) => listener.reregister(registry, token, interests),
+            Self::Tcp(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Self::Unix(listener) => listener.deregister(registry),
+            Self::Tcp(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.register(registry, token, interests),
+            Self::Tcp(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.reregister(registry, token, interests),
+            Self::Tcp(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.deregister(registry),
+            Self::Tcp(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::bus::{Builder, DeviceBus};
+    use crate::call::{Call, Invoke};
+    use crate::fault::{Fault, FaultyTransport};
+    use crate::mock::MockBus;
+    use crate::types::DeviceDescriptor;
+    use std::sync::mpsc;
+    use std::{env, process, thread};
+
+    /// Serves a mock bus with the given limit on calls, changing its first response with the given
+    /// fault if there is one, and returns the path of its socket. The multiplexer runs until the
+    /// test process exits.
+    fn serve(name: &str, mock: MockBus, fault: Option<Fault>, max_message_size: usize) -> PathBuf {
+        let path = env::temp_dir().join(format!("oc2-hlapi-{name}-{}.sock", process::id()));
+        let (ready, bound) = mpsc::channel();
+        let socket_path = path.clone();
+
+        thread::spawn(move || {
+            let mut bus = FaultyTransport::new(mock.transport());
+
+            if let Some(fault) = fault {
+                bus = bus.schedule(0, fault);
+            }
+
+            let mut multiplexer = Multiplexer::bind(&socket_path, bus)
+                .unwrap()
+                .max_message_size(max_message_size);
+
+            ready.send(()).unwrap();
+            multiplexer.run()
+        });
+
+        bound.recv().unwrap();
+        path
+    }
+
+    #[test]
+    fn damaged_response_fails_only_its_own_call() {
+        let path = serve(
+            "damaged",
+            MockBus::new(),
+            Some(Fault::Corrupt(1)),
+            MAX_MESSAGE_SIZE,
+        );
+        let first = DeviceBus::connect(&path).unwrap();
+        let second = DeviceBus::connect(&path).unwrap();
+
+        assert!(matches!(first.call(Call::list()), Err(Error::Api(_))));
+        assert!(second.call(Call::list()).is_ok());
+        assert!(first.call(Call::list()).is_ok());
+    }
+
+    /// Serves a mock bus with a device that echoes its first parameter, and returns a client with
+    /// a limit far above the default one, along with the ID of the device.
+    fn serve_echo(name: &str, max_message_size: usize) -> (DeviceBus, uuid::Uuid) {
+        let mock = MockBus::new();
+        let id = uuid::Uuid::new_v4();
+
+        mock.add_device(
+            DeviceDescriptor {
+                device_id: id,
+                type_names: Box::new(["echo".into()]),
+            },
+            [],
+        );
+        mock.on_invoke(id, "echo", |parameters| Ok(parameters[0].clone()));
+
+        let path = serve(name, mock, None, max_message_size);
+        let bus = Builder::new()
+            .max_message_size(1 << 20)
+            .connect(path)
+            .unwrap();
+
+        (bus, id)
+    }
+
+    fn echo(bus: &DeviceBus, id: uuid::Uuid, text: &str) -> Result<String> {
+        bus.call(Call::<Invoke<'_, String>>::invoke(id, "echo", &[&text]))
+            .map(|response| response.0)
+    }
+
+    #[test]
+    fn long_call_within_limit_is_forwarded() {
+        let (bus, id) = serve_echo("long-call", 1 << 20);
+        let text = "x".repeat(6000);
+
+        assert_eq!(echo(&bus, id, &text).unwrap(), text);
+    }
+
+    #[test]
+    fn call_over_limit_fails_without_disconnecting() {
+        let (bus, id) = serve_echo("over-limit", MAX_MESSAGE_SIZE);
+
+        assert!(matches!(
+            echo(&bus, id, &"x".repeat(6000)),
+            Err(Error::Api(_))
+        ));
+        assert_eq!(echo(&bus, id, "short").unwrap(), "short");
+    }
+
+    #[test]
+    fn long_hello_disconnects_client() {
+        let (ready, bound) = mpsc::channel();
+
+        thread::spawn(move || {
+            let bus = MockBus::new().transport();
+            let mut multiplexer = Multiplexer::bind_tcp("127.0.0.1:0", "secret", bus).unwrap();
+            let Listener::Tcp(listener) = &multiplexer.listener else {
+                unreachable!()
+            };
+
+            ready.send(listener.local_addr().unwrap()).unwrap();
+            multiplexer.run()
+        });
+
+        let mut stream = StdTcpStream::connect(bound.recv().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"\0{\"type\":\"hello\",\"data\":\"")
+            .unwrap();
+        stream.write_all(&[b'a'; 2 * HELLO_MAX_LEN]).unwrap();
+
+        // The multiplexer may close the connection before it has read everything, which resets it.
+        let mut reply = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut reply) {
+            assert_eq!(e.kind(), IoErrorKind::ConnectionReset);
+        }
+        assert!(reply.is_empty());
+    }
+}
@@ -1,4 +1,6 @@
-pub use crate::bus::DeviceBus;
+#[cfg(feature = "async")]
+pub use crate::async_bus::{AsyncDeviceBus, AsyncRpcBus};
+pub use crate::bus::{DeviceBus, RpcBus, SyncDeviceBus};
 pub use crate::call::{
     ApiCall, Call, Invoke as InvokeCall, List as ListCall, Methods as MethodsCall,
 };
@@ -6,4 +8,5 @@ pub use crate::device::*;
 pub use crate::response::{
     List as ListResponse, Methods as MethodsResponse, Response, Return as ReturnResponse,
 };
+pub use crate::transport::{Duplex, Transport, Tty};
 pub use crate::types::*;
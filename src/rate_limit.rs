@@ -0,0 +1,288 @@
+//! Limiting how often calls are made, so that they are delayed rather than throttled by the game.
+//!
+//! OC2 limits how often a VM may call methods on its devices, and calls over that limit fail or
+//! stall. A [`RateLimiter`] keeps calls under a limit of its own instead, using a token bucket for
+//! all calls together and one for each device which has a limit:
+//!
+//! ```no_run
+//! # use oc2_hlapi::prelude::*;
+//! # use oc2_hlapi::rate_limit::{RateLimit, RateLimiter};
+//! # fn main() -> oc2_hlapi::error::Result<()> {
+//! let bus = DeviceBus::new("/dev/hvc0")?;
+//! let limiter = RateLimiter::new()
+//!     .global(RateLimit::new(20.0, 20))
+//!     .each_device(RateLimit::new(5.0, 5));
+//!
+//! bus.set_rate_limiter(Some(limiter.clone()));
+//! // ... and later:
+//! println!("{:?}", limiter.stats());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::{Error, Result};
+use crate::trace;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A rate that calls are allowed at, with room for short bursts of calls above it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// Creates a limit which allows the given number of calls per second on average, and up to
+    /// `burst` calls in a row when no calls have been made for a while.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` isn't positive or `burst` is zero.
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        assert!(per_second > 0.0, "rate limit must be positive");
+        assert!(burst > 0, "rate limit burst must not be zero");
+
+        Self { per_second, burst }
+    }
+}
+
+/// How much a [`RateLimiter`] has delayed calls so far.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct RateLimitStats {
+    /// The number of calls which went through the limiter.
+    pub calls: u64,
+    /// The number of calls which had to be delayed.
+    pub throttled: u64,
+    /// The total time calls were delayed for.
+    pub delay: Duration,
+}
+
+/// A limiter for the calls made on one or more buses.
+///
+/// Clones of a limiter share its limits and counters, so that one clone can be given to a bus while
+/// another is kept to read the counters, or the same limits can be shared by several buses.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    global: Option<Bucket>,
+    devices: HashMap<uuid::Uuid, Bucket>,
+    each_device: Option<RateLimit>,
+    stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    /// Creates a limiter without any limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the rate of all calls together.
+    pub fn global(self, limit: RateLimit) -> Self {
+        self.state().global = Some(Bucket::new(limit));
+        self
+    }
+
+    /// Limits the rate of calls made on the device with the given ID. This takes precedence over
+    /// [`each_device`](Self::each_device).
+    pub fn device(self, id: uuid::Uuid, limit: RateLimit) -> Self {
+        self.state().devices.insert(id, Bucket::new(limit));
+        self
+    }
+
+    /// Limits the rate of calls made on every device which doesn't have a limit of its own.
+    pub fn each_device(self, limit: RateLimit) -> Self {
+        self.state().each_device = Some(limit);
+        self
+    }
+
+    /// Returns how much calls have been delayed so far.
+    pub fn stats(&self) -> RateLimitStats {
+        self.state().stats
+    }
+
+    /// Waits until a call can be made on the given device, if any. Fails with [`Error::Timeout`]
+    /// without waiting if that would take until after the deadline.
+    pub(crate) fn acquire(
+        &self,
+        device_id: Option<uuid::Uuid>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let mut delay = Duration::ZERO;
+
+        loop {
+            let wait = {
+                let mut state = self.state();
+                let wait = state.try_acquire(device_id, Instant::now());
+
+                if wait.is_zero() {
+                    state.stats.calls += 1;
+
+                    if !delay.is_zero() {
+                        state.stats.throttled += 1;
+                        state.stats.delay += delay;
+                    }
+
+                    return Ok(());
+                }
+
+                wait
+            };
+
+            if deadline.is_some_and(|deadline| Instant::now() + wait > deadline) {
+                return Err(Error::Timeout);
+            }
+
+            trace::throttled(wait);
+            sleep(wait);
+            delay += wait;
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // Every bucket holds a valid count and refill time on its own, so a panic between two
+        // bucket updates at worst lets one call through without a token from each bucket.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl State {
+    /// Takes a token from every bucket which applies to a call if all of them have one, and
+    /// otherwise returns how long it will be until they do.
+    fn try_acquire(&mut self, device_id: Option<uuid::Uuid>, now: Instant) -> Duration {
+        let device = match device_id {
+            Some(id) => match (self.devices.contains_key(&id), self.each_device) {
+                (false, Some(limit)) => Some(self.devices.entry(id).or_insert(Bucket::new(limit))),
+                _ => self.devices.get_mut(&id),
+            },
+            None => None,
+        };
+
+        let mut buckets = [self.global.as_mut(), device];
+        let wait = buckets
+            .iter_mut()
+            .flatten()
+            .map(|bucket| bucket.wait(now))
+            .max()
+            .unwrap_or_default();
+
+        if wait.is_zero() {
+            for bucket in buckets.into_iter().flatten() {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        wait
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket, then returns how long it will be until it has a token.
+    fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst.into());
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.limit.per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A limiter state with only a global limit, and the instant its bucket was filled at.
+    fn global(limit: RateLimit) -> (State, Instant) {
+        let bucket = Bucket::new(limit);
+        let start = bucket.updated;
+        let state = State {
+            global: Some(bucket),
+            ..State::default()
+        };
+
+        (state, start)
+    }
+
+    #[test]
+    fn burst_is_allowed_at_once() {
+        let (mut state, start) = global(RateLimit::new(10.0, 3));
+
+        for _ in 0..3 {
+            assert_eq!(state.try_acquire(None, start), Duration::ZERO);
+        }
+
+        assert_eq!(state.try_acquire(None, start), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn tokens_refill_over_time_up_to_burst() {
+        let (mut state, start) = global(RateLimit::new(10.0, 3));
+
+        for _ in 0..3 {
+            state.try_acquire(None, start);
+        }
+
+        let later = start + Duration::from_millis(100);
+        assert_eq!(state.try_acquire(None, later), Duration::ZERO);
+        assert!(!state.try_acquire(None, later).is_zero());
+
+        // However long the bucket was left alone, it only holds as many tokens as the burst.
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(state.try_acquire(None, much_later), Duration::ZERO);
+        }
+        assert!(!state.try_acquire(None, much_later).is_zero());
+    }
+
+    #[test]
+    fn wait_is_time_until_next_token() {
+        let (mut state, start) = global(RateLimit::new(10.0, 1));
+        state.try_acquire(None, start);
+
+        let wait = state.try_acquire(None, start + Duration::from_millis(40));
+        assert!(wait.abs_diff(Duration::from_millis(60)) < Duration::from_micros(1));
+
+        // Asking again without taking a token doesn't make the wait any longer.
+        let wait = state.try_acquire(None, start + Duration::from_millis(70));
+        assert!(wait.abs_diff(Duration::from_millis(30)) < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn token_is_only_taken_when_every_bucket_has_one() {
+        let (mut state, start) = global(RateLimit::new(10.0, 2));
+        let device = uuid::Uuid::new_v4();
+        state
+            .devices
+            .insert(device, Bucket::new(RateLimit::new(1.0, 1)));
+
+        assert_eq!(state.try_acquire(Some(device), start), Duration::ZERO);
+        assert!(!state.try_acquire(Some(device), start).is_zero());
+
+        // The failed call above didn't use up the global bucket's last token.
+        assert_eq!(state.try_acquire(None, start), Duration::ZERO);
+    }
+}
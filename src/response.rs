@@ -1,8 +1,12 @@
 use crate::call::ApiCall;
 use crate::error::{Error, Result};
-use crate::types::{DeviceDescriptor, MethodDescriptor};
-use serde::de::{self, DeserializeOwned};
-use serde::Deserialize;
+use crate::types::{DeviceDescriptor, DeviceDescriptorRef, MethodDescriptor, MethodDescriptorRef};
+use serde::de;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::borrow::Cow;
 use std::mem::MaybeUninit;
 use std::result::Result as StdResult;
 
@@ -14,16 +18,56 @@ pub enum Response<T: ApiCall> {
     Error(String),
 }
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
+impl<T: ApiCall> Serialize for Response<T>
+where
+    T::Response: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut s = serializer.serialize_struct("RpcResponse", 2)?;
+
+        match self {
+            Response::Response(data) => {
+                s.serialize_field("type", T::RESPONSE_KIND)?;
+
+                // Mirrors the HLAPI, which leaves out the `data` field for void return values.
+                if std::mem::size_of::<T::Response>() != 0 {
+                    s.serialize_field("data", data)?;
+                }
+            }
+            Response::Error(e) => {
+                s.serialize_field("type", "error")?;
+                s.serialize_field("data", e)?;
+            }
+        }
+
+        s.end()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize)]
 pub struct List(pub Box<[DeviceDescriptor]>);
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize)]
 pub struct Methods(pub Box<[MethodDescriptor]>);
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+/// The response to a `list` call, borrowing its strings from the response where possible. See
+/// [`ResponseData`].
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct ListRef<'a>(#[serde(borrow)] pub Vec<DeviceDescriptorRef<'a>>);
+
+/// The response to a `methods` call, borrowing its strings from the response where possible. See
+/// [`ResponseData`].
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct MethodsRef<'a>(#[serde(borrow)] pub Vec<MethodDescriptorRef<'a>>);
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize)]
+#[serde(transparent)]
 pub struct Return<R>(pub R);
 
-impl<'de, R: DeserializeOwned + 'static> Deserialize<'de> for Return<R> {
+impl<'de, R: Deserialize<'de>> Deserialize<'de> for Return<R> {
     fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -69,3 +113,60 @@ impl<T: ApiCall> From<Response<T>> for Result<T::Response> {
         }
     }
 }
+
+/// The data of a successful response, borrowed from the buffer the response was read into.
+///
+/// This is what [`DeviceBus::call_with`](crate::DeviceBus::call_with) passes to its closure. The
+/// data can be deserialized into types which borrow from it, such as [`ListRef`], [`MethodsRef`]
+/// or a [`Return`] of a type with `&str` or `Cow<str>` fields, to avoid allocating a copy of every
+/// string in the response.
+#[derive(Copy, Clone, Debug)]
+pub struct ResponseData<'a>(Option<&'a RawValue>);
+
+impl<'a> ResponseData<'a> {
+    pub(crate) fn new(data: Option<&'a RawValue>) -> Self {
+        Self(data)
+    }
+
+    /// Returns the data as raw JSON, or `None` if the response didn't have any, as is the case for
+    /// methods which don't return anything.
+    pub fn json(&self) -> Option<&'a str> {
+        self.0.map(RawValue::get)
+    }
+
+    /// Deserializes the data, borrowing from the response where possible. A response without any
+    /// data is deserialized from `null`.
+    pub fn deserialize<D: Deserialize<'a>>(&self) -> Result<D> {
+        match self.0 {
+            Some(data) => Ok(serde_json::from_str(data.get())?),
+            None => Ok(D::deserialize(Value::Null)?),
+        }
+    }
+}
+
+/// The parts of a response which are needed to tell whether it was successful.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(borrow, default)]
+    data: Option<&'a RawValue>,
+}
+
+/// Splits a message without its delimiters into the data of a successful response, or the error it
+/// contains.
+pub(crate) fn decode_borrowed(message: &[u8]) -> Result<ResponseData<'_>> {
+    let envelope = serde_json::from_slice::<Envelope<'_>>(message)?;
+
+    if envelope.kind == "error" {
+        let message = envelope
+            .data
+            .map(|data| serde_json::from_str::<Cow<'_, str>>(data.get()))
+            .transpose()?
+            .unwrap_or_default();
+
+        return Err(Error::from(&*message));
+    }
+
+    Ok(ResponseData(envelope.data))
+}
@@ -0,0 +1,291 @@
+//! Retrying calls which failed for reasons that are likely to go away on their own.
+//!
+//! A call can fail with [`Error::Api`] because the block it was made on was briefly unloaded or
+//! busy, or with [`Error::ReadZero`] when the VM stalls. A [`RetryPolicy`] makes such calls again,
+//! waiting a little longer after each failed attempt:
+//!
+//! ```no_run
+//! # use oc2_hlapi::prelude::*;
+//! # use oc2_hlapi::retry::RetryPolicy;
+//! # use std::time::Duration;
+//! # fn main() -> oc2_hlapi::error::Result<()> {
+//! let bus = DeviceBus::new("/dev/hvc0")?;
+//!
+//! bus.set_retry_policy(Some(
+//!     RetryPolicy::new(5)
+//!         .backoff(Duration::from_millis(50), Duration::from_secs(1))
+//!         .retry_method("getEnergyStored"),
+//! ));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Making an `invoke` call twice can have a side effect twice, such as moving a robot two blocks
+//! instead of one, so `invoke` calls are only retried for the methods a policy allows explicitly.
+//! `list` and `methods` calls don't change anything, and are always retried.
+
+use crate::call::{ApiCall, Call};
+use crate::error::{Error, Result};
+use crate::trace;
+use std::collections::HashSet;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// When and how often to retry a call which failed.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    predicate: Arc<dyn Fn(&Error) -> bool + Send + Sync>,
+    methods: HashSet<Box<str>>,
+    all_methods: bool,
+}
+
+impl RetryPolicy {
+    /// Creates a policy which makes a call at most the given number of times, including the first
+    /// attempt, as long as it fails with a [transient](Self::is_transient) error.
+    ///
+    /// The delay between attempts starts at 50 milliseconds, about one game tick, and doubles after
+    /// every attempt up to one second.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            predicate: Arc::new(Self::is_transient),
+            methods: HashSet::new(),
+            all_methods: false,
+        }
+    }
+
+    /// Sets the delay before the second attempt, which doubles after every attempt after that up to
+    /// the given maximum.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets which errors a call is retried after. By default, these are the
+    /// [transient](Self::is_transient) ones.
+    pub fn retry_if<F: Fn(&Error) -> bool + Send + Sync + 'static>(mut self, predicate: F) -> Self {
+        self.predicate = Arc::new(predicate);
+        self
+    }
+
+    /// Allows `invoke` calls of the method with the given name to be retried. Only allow this for
+    /// methods which can be called twice without doing something twice.
+    pub fn retry_method(mut self, name: &str) -> Self {
+        self.methods.insert(name.into());
+        self
+    }
+
+    /// Allows every `invoke` call to be retried, including ones with side effects.
+    pub fn retry_all_methods(mut self) -> Self {
+        self.all_methods = true;
+        self
+    }
+
+    /// Returns whether an error is one which is likely to go away by itself, which are
+    /// [`Error::Api`] and [`Error::ReadZero`].
+    pub fn is_transient(error: &Error) -> bool {
+        matches!(error, Error::Api(_) | Error::ReadZero)
+    }
+
+    /// Returns whether a call may be retried under this policy at all.
+    fn allows<C: ApiCall>(&self, call: &Call<C>) -> bool {
+        match call.method_name() {
+            Some(name) => self.all_methods || self.methods.contains(name),
+            None => true,
+        }
+    }
+
+    /// The delay after the given number of failed attempts.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// A policy which makes calls up to three times.
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("methods", &self.methods)
+            .field("all_methods", &self.all_methods)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Makes a call with `attempt` until it succeeds or the policy gives up on it. No attempt is made
+/// after the deadline, if there is one.
+pub(crate) fn run<C: ApiCall, R>(
+    policy: Option<&RetryPolicy>,
+    call: &Call<C>,
+    deadline: Option<Instant>,
+    mut attempt: impl FnMut() -> Result<R>,
+) -> Result<R> {
+    let Some(policy) = policy.filter(|policy| policy.allows(call)) else {
+        return attempt();
+    };
+
+    let mut attempts = 1;
+
+    loop {
+        match attempt() {
+            Err(e) if attempts < policy.max_attempts && (policy.predicate)(&e) => {
+                let delay = policy.delay(attempts);
+
+                if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                    return Err(e);
+                }
+
+                trace::retry(attempts, &e, delay);
+                sleep(delay);
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "mock")]
+    use crate::call::Invoke;
+    #[cfg(feature = "mock")]
+    use crate::mock::MockBus;
+    #[cfg(feature = "mock")]
+    use crate::response::Return;
+    #[cfg(feature = "mock")]
+    use crate::types::DeviceDescriptor;
+    #[cfg(feature = "mock")]
+    use uuid::Uuid;
+
+    #[cfg(feature = "mock")]
+    fn add_device(mock: &MockBus) -> Uuid {
+        let id = Uuid::new_v4();
+
+        mock.add_device(
+            DeviceDescriptor {
+                device_id: id,
+                type_names: Box::new(["test".into()]),
+            },
+            [],
+        );
+
+        id
+    }
+
+    #[cfg(feature = "mock")]
+    fn invoke(id: Uuid, name: &str) -> Call<Invoke<'_, i32>> {
+        Call::invoke(id, name, &[])
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy =
+            RetryPolicy::new(10).backoff(Duration::from_millis(10), Duration::from_millis(50));
+        let delays = (1..6)
+            .map(|attempt| policy.delay(attempt))
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, [10, 20, 40, 50, 50].map(Duration::from_millis));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn only_allowed_methods_are_retried() {
+        let mock = MockBus::new();
+        let id = add_device(&mock);
+        mock.fails(id, "move", "busy");
+        mock.fails(id, "getEnergyStored", "busy");
+
+        let bus = mock.bus();
+        bus.set_retry_policy(Some(
+            RetryPolicy::new(3)
+                .backoff(Duration::from_millis(1), Duration::from_millis(1))
+                .retry_method("getEnergyStored"),
+        ));
+
+        assert!(matches!(bus.call(invoke(id, "move")), Err(Error::Api(_))));
+        assert_eq!(mock.calls().len(), 1);
+
+        mock.clear_calls();
+        assert!(matches!(
+            bus.call(invoke(id, "getEnergyStored")),
+            Err(Error::Api(_))
+        ));
+        assert_eq!(mock.calls().len(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn retries_until_call_succeeds() {
+        let mock = MockBus::new();
+        let id = add_device(&mock);
+        let mut failures = 2;
+
+        mock.on_invoke(id, "getEnergyStored", move |_| {
+            if failures == 0 {
+                return Ok(42.into());
+            }
+
+            failures -= 1;
+            Err(String::from("busy"))
+        });
+
+        let bus = mock.bus();
+        bus.set_retry_policy(Some(
+            RetryPolicy::new(3)
+                .backoff(Duration::from_millis(1), Duration::from_millis(1))
+                .retry_method("getEnergyStored"),
+        ));
+
+        assert!(matches!(
+            bus.call(invoke(id, "getEnergyStored")),
+            Ok(Return(42))
+        ));
+        assert_eq!(mock.calls().len(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn backoff_stops_at_deadline() {
+        let mock = MockBus::new();
+        let id = add_device(&mock);
+        mock.fails(id, "getEnergyStored", "busy");
+
+        let bus = mock.bus();
+        bus.set_retry_policy(Some(
+            RetryPolicy::new(10)
+                .backoff(Duration::from_millis(100), Duration::from_secs(1))
+                .retry_method("getEnergyStored"),
+        ));
+
+        // The second attempt is made after 100 ms, but the one after that would only be made
+        // after another 200 ms, which is past the deadline.
+        let start = Instant::now();
+        let result =
+            bus.call_with_timeout(invoke(id, "getEnergyStored"), Duration::from_millis(250));
+
+        assert!(matches!(result, Err(Error::Api(_))));
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert_eq!(mock.calls().len(), 2);
+    }
+}
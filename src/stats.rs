@@ -0,0 +1,301 @@
+//! Counting the calls made on a bus and how long they took.
+//!
+//! A [`Stats`] collector added to a bus with [`DeviceBus::set_stats`] counts calls, errors and
+//! bytes, and keeps a histogram of latencies, both for each kind of call and for each method of
+//! each type of device:
+//!
+//! ```no_run
+//! # use oc2_hlapi::prelude::*;
+//! # use oc2_hlapi::stats::Stats;
+//! # fn main() -> oc2_hlapi::error::Result<()> {
+//! let bus = DeviceBus::new("/dev/hvc0")?;
+//! let stats = Stats::new();
+//!
+//! bus.set_stats(Some(stats.clone()));
+//! // ... and later:
+//! for (key, method) in &stats.snapshot().by_method {
+//!     println!("{key}: {} calls, p99 {:?}", method.calls, method.latency.quantile(0.99));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The type of a device is learned from the `list` calls made on the bus, such as the one made to
+//! find it. Calls on devices which haven't been listed yet are counted under their ID instead.
+//!
+//! [`DeviceBus::set_stats`]: crate::DeviceBus::set_stats
+
+use crate::call::{ApiCall, Call};
+use crate::response;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// A collector of statistics about the calls made on one or more buses.
+///
+/// Clones of a collector share its statistics, so that one clone can be given to a bus while
+/// another is kept to take snapshots.
+#[derive(Clone, Debug, Default)]
+pub struct Stats(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    snapshot: StatsSnapshot,
+    device_types: HashMap<uuid::Uuid, Box<str>>,
+}
+
+impl Stats {
+    /// Creates a collector which hasn't counted any calls yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the statistics collected so far.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.state().snapshot.clone()
+    }
+
+    /// Forgets the statistics collected so far, but not the types of the devices.
+    pub fn reset(&self) {
+        self.state().snapshot = StatsSnapshot::default();
+    }
+
+    /// Remembers the types of the devices in the response to a `list` call.
+    pub(crate) fn learn_devices(&self, list: &response::List) {
+        let mut state = self.state();
+
+        for device in list.0.iter() {
+            state
+                .device_types
+                .insert(device.device_id, device.type_names.join(",").into());
+        }
+    }
+
+    /// Counts a finished call.
+    pub(crate) fn record<C: ApiCall>(
+        &self,
+        call: &Call<C>,
+        failed: bool,
+        latency: Duration,
+        bytes_sent: u64,
+        bytes_received: u64,
+    ) {
+        let mut state = self.state();
+
+        let record = |stats: &mut CallStats| {
+            stats.calls += 1;
+            stats.errors += u64::from(failed);
+            stats.bytes_sent += bytes_sent;
+            stats.bytes_received += bytes_received;
+            stats.latency.record(latency);
+        };
+
+        record(state.snapshot.by_kind.entry(call.kind()).or_default());
+
+        if let (Some(device_id), Some(method_name)) = (call.device_id(), call.method_name()) {
+            let device_type = match state.device_types.get(&device_id) {
+                Some(device_type) => device_type.clone(),
+                None => device_id.to_string().into(),
+            };
+
+            let key = MethodKey {
+                device_type,
+                method_name: method_name.into(),
+            };
+
+            record(state.snapshot.by_method.entry(key).or_default());
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic while recording a call can at worst leave it counted in some statistics and not
+        // others, which isn't worth losing everything collected so far over.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The statistics collected by [`Stats`] at one point in time.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct StatsSnapshot {
+    /// Statistics for each kind of call, such as `list` or `invoke`.
+    pub by_kind: BTreeMap<&'static str, CallStats>,
+    /// Statistics for each method invoked on each type of device.
+    pub by_method: BTreeMap<MethodKey, CallStats>,
+}
+
+/// A method of a type of device.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct MethodKey {
+    /// The type names the device was listed with, joined by commas, or its ID if it wasn't listed.
+    pub device_type: Box<str>,
+    /// The name of the method.
+    pub method_name: Box<str>,
+}
+
+impl Display for MethodKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.device_type, self.method_name)
+    }
+}
+
+/// Statistics about a group of calls.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct CallStats {
+    /// The number of calls made.
+    pub calls: u64,
+    /// The number of calls which failed, for any reason.
+    pub errors: u64,
+    /// The number of bytes written for the calls, including delimiters.
+    pub bytes_sent: u64,
+    /// The number of bytes read for the calls, including delimiters.
+    pub bytes_received: u64,
+    /// The time from writing each call to reading its response.
+    pub latency: Histogram,
+}
+
+/// The number of buckets in a [`Histogram`]. The last one holds every latency of 2^24
+/// microseconds, about 17 seconds, or longer.
+const BUCKETS: usize = 26;
+
+/// A histogram of latencies, in buckets whose bounds are powers of two microseconds.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    /// The number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The sum of the latencies recorded.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// The mean of the latencies recorded, or zero if there are none.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => self.total.div_f64(count as f64),
+        }
+    }
+
+    /// The longest latency recorded.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// An upper bound for the given quantile of the latencies recorded, such as `0.5` for the
+    /// median. This is the upper bound of the bucket the quantile falls into, but never more than
+    /// the longest latency recorded.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = (quantile.clamp(0.0, 1.0) * self.count() as f64).ceil() as u64;
+        let mut seen = 0;
+
+        for (bound, count) in self.buckets() {
+            seen += count;
+
+            if seen >= rank.max(1) {
+                return bound.min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// Iterates over the buckets of the histogram, as their exclusive upper bound and the number of
+    /// latencies in them. The bound of the last bucket is [`Duration::MAX`].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, &count)| {
+            let bound = if i == BUCKETS - 1 {
+                Duration::MAX
+            } else {
+                Duration::from_micros(1 << i)
+            };
+
+            (bound, count)
+        })
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        // The bucket whose bound is the smallest power of two greater than the latency.
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(micros: &[u64]) -> Histogram {
+        let mut histogram = Histogram::default();
+
+        for &latency in micros {
+            histogram.record(Duration::from_micros(latency));
+        }
+
+        histogram
+    }
+
+    fn bucket(histogram: &Histogram, bound: Duration) -> u64 {
+        histogram
+            .buckets()
+            .find(|&(b, _)| b == bound)
+            .map_or(0, |(_, count)| count)
+    }
+
+    #[test]
+    fn empty_histogram_quantiles_are_zero() {
+        let histogram = Histogram::default();
+
+        for quantile in [0.0, 0.5, 1.0] {
+            assert_eq!(histogram.quantile(quantile), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn bucket_bounds_are_exclusive() {
+        let histogram = histogram(&[0, 1023, 1024]);
+
+        assert_eq!(bucket(&histogram, Duration::from_micros(1)), 1);
+        assert_eq!(bucket(&histogram, Duration::from_micros(1024)), 1);
+        assert_eq!(bucket(&histogram, Duration::from_micros(2048)), 1);
+    }
+
+    #[test]
+    fn quantile_is_bound_of_its_bucket() {
+        let histogram = histogram(&[3, 3, 3, 3, 3, 3, 3, 3, 3, 1000]);
+
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(4));
+        assert_eq!(histogram.quantile(0.9), Duration::from_micros(4));
+        assert_eq!(histogram.quantile(0.95), Duration::from_micros(1000));
+    }
+
+    #[test]
+    fn extreme_quantiles_are_first_and_last_buckets() {
+        let histogram = histogram(&[3, 5, 1000]);
+
+        assert_eq!(histogram.quantile(0.0), Duration::from_micros(4));
+        // The last bucket's bound is 1024 µs, but nothing longer than 1000 µs was recorded.
+        assert_eq!(histogram.quantile(1.0), Duration::from_micros(1000));
+        assert_eq!(histogram.quantile(2.0), Duration::from_micros(1000));
+    }
+
+    #[test]
+    fn latency_past_last_bound_is_counted_in_last_bucket() {
+        let histogram = histogram(&[u64::MAX]);
+
+        assert_eq!(bucket(&histogram, Duration::MAX), 1);
+        assert_eq!(histogram.quantile(1.0), Duration::from_micros(u64::MAX));
+    }
+}
@@ -0,0 +1,173 @@
+//! Instrumentation of HLAPI calls, which is only compiled in with the `tracing` feature.
+//!
+//! Every call is wrapped in an `hlapi_call` span at the debug level, which records the kind of
+//! call, the device and method it was made on, the size of the serialized call and how long the
+//! call took. Raw messages are logged at the trace level, retries and calls delayed by a rate
+//! limiter at the debug level, and garbage skipped on the bus and reconnections at the warn level.
+
+use crate::call::{ApiCall, Call};
+use crate::error::{Error, Result};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::time::Duration;
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+#[cfg(all(feature = "tracing", feature = "async"))]
+use tracing::Instrument;
+#[cfg(feature = "tracing")]
+use tracing::{field, Span};
+
+/// Runs a blocking call inside its span.
+pub(crate) fn call<C: ApiCall, R>(call: &Call<C>, f: impl FnOnce() -> Result<R>) -> Result<R> {
+    #[cfg(feature = "tracing")]
+    {
+        let span = span(call);
+        let start = Instant::now();
+        let result = span.in_scope(f);
+
+        span.in_scope(|| finish(start, &result));
+        result
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = call;
+        f()
+    }
+}
+
+/// Creates the span of an asynchronous call. Since asynchronous calls are serialized before they
+/// are sent, the serialized message is logged here rather than when it is written.
+#[cfg(feature = "async")]
+pub(crate) fn async_call<C: ApiCall>(call: &Call<C>, message: &[u8]) -> CallSpan {
+    #[cfg(feature = "tracing")]
+    {
+        let span = span(call);
+        span.in_scope(|| message_sent(message));
+
+        CallSpan(span)
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (call, message);
+        CallSpan(())
+    }
+}
+
+/// The span of an asynchronous call. This doesn't depend on the type of the call, which usually
+/// borrows its parameters, so that it can be moved into the call's future.
+#[cfg(feature = "async")]
+pub(crate) struct CallSpan(
+    #[cfg(feature = "tracing")] Span,
+    #[cfg(not(feature = "tracing"))] (),
+);
+
+#[cfg(feature = "async")]
+impl CallSpan {
+    /// Runs the future of the call inside the span.
+    pub(crate) async fn instrument<R, F>(self, future: F) -> Result<R>
+    where
+        F: Future<Output = Result<R>>,
+    {
+        #[cfg(feature = "tracing")]
+        {
+            let span = self.0;
+            let start = Instant::now();
+            let result = future.instrument(span.clone()).await;
+
+            span.in_scope(|| finish(start, &result));
+            result
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = self;
+            future.await
+        }
+    }
+}
+
+/// Logs a serialized call, including its delimiters, and records its size in the current span.
+pub(crate) fn message_sent(message: &[u8]) {
+    #[cfg(feature = "tracing")]
+    {
+        let size = message.len().saturating_sub(2);
+
+        Span::current().record("size", size);
+        tracing::trace!(message = %String::from_utf8_lossy(message).trim_matches('\0'), "sent");
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = message;
+}
+
+/// Logs a received message, without its delimiters.
+pub(crate) fn message_received(message: &[u8]) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(message = %String::from_utf8_lossy(message), "received");
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = message;
+}
+
+/// Logs a failed attempt at a call which is about to be retried.
+pub(crate) fn retry(attempt: u32, error: &Error, delay: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(attempt, %error, ?delay, "retrying call");
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = (attempt, error, delay);
+}
+
+/// Logs a call being delayed by a rate limiter.
+pub(crate) fn throttled(delay: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(?delay, "throttling call");
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = delay;
+}
+
+/// Logs a bus being reopened after its transport failed with the given error.
+pub(crate) fn reconnect(error: &Error) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(%error, "reopening the bus");
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = error;
+}
+
+/// Logs bytes which were skipped because they weren't a valid message.
+pub(crate) fn garbage(bytes: &[u8]) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(bytes = %String::from_utf8_lossy(bytes), "skipped garbage on the bus");
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = bytes;
+}
+
+#[cfg(feature = "tracing")]
+fn span<C: ApiCall>(call: &Call<C>) -> Span {
+    tracing::debug_span!(
+        "hlapi_call",
+        kind = call.kind(),
+        device_id = call.device_id().map(field::display),
+        method = call.method_name(),
+        size = field::Empty,
+        latency_us = field::Empty,
+    )
+}
+
+#[cfg(feature = "tracing")]
+fn finish<R>(start: Instant, result: &Result<R>) {
+    let latency = start.elapsed();
+
+    Span::current().record("latency_us", latency.as_micros() as u64);
+
+    match result {
+        Ok(_) => tracing::debug!(?latency, "call succeeded"),
+        Err(e) => tracing::debug!(?latency, error = %e, "call failed"),
+    }
+}
@@ -0,0 +1,491 @@
+//! Recording and replaying the messages sent over a device bus.
+//!
+//! A [`Recorder`] wraps the transport of a bus and writes every call and response that passes
+//! through it to a transcript in the JSON Lines format. A [`Replay`] transport later answers the
+//! same calls from that transcript, so that a session captured against the game once can be run
+//! again without it:
+//!
+//! ```no_run
+//! # use oc2_hlapi::prelude::*;
+//! # use oc2_hlapi::transcript::{Recorder, Replay};
+//! # fn main() -> oc2_hlapi::error::Result<()> {
+//! let tty = Tty::open("/dev/hvc0")?;
+//! let bus = DeviceBus::with_transport(Recorder::create(tty, "session.jsonl")?);
+//! // ... and later, without the game running:
+//! let bus = DeviceBus::with_transport(Replay::open("session.jsonl")?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::bus::FrameDecoder;
+use crate::error::{MessageKind, Result};
+use crate::transport::{PollSettings, Termios, Transport};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind as IoErrorKind, Read, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// One message in a transcript.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The number of seconds between the start of the recording and the message.
+    pub time: f64,
+    /// Whether the message was a call or a response.
+    pub kind: MessageKind,
+    /// The message itself. A message which wasn't valid JSON is recorded as a string.
+    pub message: Value,
+}
+
+/// A transport which records every message sent and received over another transport.
+///
+/// Each message is written to the transcript as soon as the whole of it has passed through, as one
+/// [`Entry`] per line. The bytes of a read or write have already been passed on by the time they
+/// are recorded, so a failure to write to the transcript is returned from the next write instead,
+/// before any of it is passed on.
+#[derive(Debug)]
+pub struct Recorder<T, W = BufWriter<File>> {
+    transport: T,
+    writer: W,
+    start: Instant,
+    calls: FrameDecoder,
+    responses: FrameDecoder,
+    // The first failure to write to the transcript since the last write.
+    error: Option<io::Error>,
+}
+
+impl<T: Transport> Recorder<T, BufWriter<File>> {
+    /// Records the messages sent over the given transport to a new transcript file at the specified
+    /// path, replacing any file which is already there.
+    pub fn create<P: AsRef<Path>>(transport: T, path: P) -> Result<Self> {
+        Ok(Self::new(transport, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<T: Transport, W: Write> Recorder<T, W> {
+    /// Records the messages sent over the given transport to the given writer.
+    pub fn new(transport: T, writer: W) -> Self {
+        Self {
+            transport,
+            writer,
+            start: Instant::now(),
+            calls: FrameDecoder::new(),
+            responses: FrameDecoder::new(),
+            error: None,
+        }
+    }
+
+    /// Stops recording, returning the transport and the writer.
+    pub fn into_inner(self) -> (T, W) {
+        (self.transport, self.writer)
+    }
+}
+
+impl<T: Transport, W: Write> Read for Recorder<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.transport.read(buf)?;
+
+        self.responses.push(&buf[..len]);
+        let recorded = record(
+            &mut self.responses,
+            &mut self.writer,
+            self.start,
+            MessageKind::Response,
+        );
+
+        // Failing the read would lose the bytes which were just received.
+        if let Err(e) = recorded {
+            self.error.get_or_insert(e);
+        }
+
+        Ok(len)
+    }
+}
+
+impl<T: Transport, W: Write> Write for Recorder<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        let len = self.transport.write(buf)?;
+
+        self.calls.push(&buf[..len]);
+        let recorded = record(
+            &mut self.calls,
+            &mut self.writer,
+            self.start,
+            MessageKind::Call,
+        );
+
+        // The call has gone out already, so the bus has to see it succeed in order to wait for its
+        // response.
+        if let Err(e) = recorded {
+            self.error.get_or_insert(e);
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.transport.set_nonblocking(nonblocking)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.transport.raw_fd()
+    }
+
+    fn configure(&mut self, f: &mut dyn FnMut(&mut Termios)) -> io::Result<()> {
+        self.transport.configure(f)
+    }
+
+    fn configure_poll(&mut self, f: &mut dyn FnMut(&mut PollSettings)) -> io::Result<()> {
+        self.transport.configure_poll(f)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        T::close(Box::new(self.transport))
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.transport.reopen()?;
+
+        // Neither half of a message cut off by the old transport is ever completed.
+        self.calls.clear();
+        self.responses.clear();
+
+        Ok(())
+    }
+}
+
+fn record<W: Write>(
+    decoder: &mut FrameDecoder,
+    writer: &mut W,
+    start: Instant,
+    kind: MessageKind,
+) -> io::Result<()> {
+    let mut recorded = false;
+
+    while let Some(message) = decoder.next_unbounded_frame() {
+        let entry = Entry {
+            time: start.elapsed().as_secs_f64(),
+            kind,
+            message: parse(message),
+        };
+
+        serde_json::to_writer(&mut *writer, &entry)?;
+        writer.write_all(b"\n")?;
+        recorded = true;
+    }
+
+    // Flushing after every message keeps the transcript intact if the program crashes, which is
+    // often exactly the session that needs replaying.
+    if recorded {
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn parse(message: &[u8]) -> Value {
+    serde_json::from_slice(message)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(message).into_owned()))
+}
+
+/// A transport which answers calls from a transcript made by a [`Recorder`].
+///
+/// Every call written to a replay has to match the next call in the transcript, and is answered
+/// with the responses which were recorded after it. A call which doesn't match fails with an
+/// [`IoErrorKind::InvalidData`] error wrapping a [`Divergence`], and so does every call after it.
+/// Reading when none of the recorded responses are left fails with [`IoErrorKind::TimedOut`], just
+/// like a call which never got a response when the transcript was recorded.
+#[derive(Debug)]
+pub struct Replay {
+    entries: VecDeque<Entry>,
+    // The number of calls which have matched the transcript so far.
+    calls: usize,
+    input: FrameDecoder,
+    output: VecDeque<u8>,
+    nonblocking: bool,
+    divergence: Option<Divergence>,
+}
+
+impl Replay {
+    /// Loads the transcript at the specified path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Loads a transcript from the given reader.
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut entries = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+
+        Ok(Self::new(entries))
+    }
+
+    /// Creates a replay of the given transcript entries.
+    pub fn new<I: IntoIterator<Item = Entry>>(entries: I) -> Self {
+        let mut replay = Self {
+            entries: entries.into_iter().collect(),
+            calls: 0,
+            input: FrameDecoder::new(),
+            output: VecDeque::new(),
+            nonblocking: false,
+            divergence: None,
+        };
+
+        // Responses recorded before the first call are available right away.
+        replay.queue_responses();
+        replay
+    }
+
+    /// Returns whether every call in the transcript has been made.
+    pub fn is_finished(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.kind != MessageKind::Call)
+    }
+
+    fn queue_responses(&mut self) {
+        while let Some(entry) = self.entries.pop_front() {
+            if entry.kind == MessageKind::Call {
+                self.entries.push_front(entry);
+                break;
+            }
+
+            self.output.push_back(b'\0');
+            // Serializing a `Value` into a writer which never fails can't fail either.
+            serde_json::to_writer(&mut self.output, &entry.message)
+                .expect("transcript message failed to serialize");
+            self.output.push_back(b'\0');
+        }
+    }
+
+    fn diverged(divergence: &Divergence) -> io::Error {
+        io::Error::new(IoErrorKind::InvalidData, divergence.clone())
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() {
+            let kind = if self.nonblocking {
+                IoErrorKind::WouldBlock
+            } else {
+                IoErrorKind::TimedOut
+            };
+
+            return Err(kind.into());
+        }
+
+        self.output.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(divergence) = &self.divergence {
+            return Err(Self::diverged(divergence));
+        }
+
+        self.input.push(buf);
+
+        while let Some(message) = self.input.next_unbounded_frame() {
+            let actual = parse(message);
+
+            match self.entries.front() {
+                Some(entry) if entry.message == actual => {
+                    self.entries.pop_front();
+                    self.calls += 1;
+                }
+                expected => {
+                    let divergence = Divergence {
+                        call: self.calls,
+                        expected: expected.map(|entry| entry.message.clone()),
+                        actual,
+                    };
+
+                    let error = Self::diverged(&divergence);
+                    self.divergence = Some(divergence);
+
+                    return Err(error);
+                }
+            }
+
+            self.queue_responses();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+/// A call which didn't match the transcript being replayed.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Divergence {
+    /// The index of the call among all calls made to the replay, starting at zero.
+    pub call: usize,
+    /// The call which the transcript expected, or `None` if it didn't expect any more calls.
+    pub expected: Option<Value>,
+    /// The call which was actually made.
+    pub actual: Value,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.expected {
+            Some(expected) => write!(
+                f,
+                "call {} diverged from the transcript: expected {expected}, but got {}",
+                self.call, self.actual,
+            ),
+            None => write!(
+                f,
+                "call {} diverged from the transcript: expected no more calls, but got {}",
+                self.call, self.actual,
+            ),
+        }
+    }
+}
+
+impl StdError for Divergence {}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::bus::DeviceBus;
+    use crate::call::{Call, Invoke};
+    use crate::error::Error;
+    use crate::mock::MockBus;
+    use crate::response::Return;
+    use crate::types::DeviceDescriptor;
+    use std::{env, fs, process};
+    use uuid::Uuid;
+
+    fn mock_with_device() -> (MockBus, Uuid) {
+        let mock = MockBus::new();
+        let id = Uuid::new_v4();
+
+        mock.add_device(
+            DeviceDescriptor {
+                device_id: id,
+                type_names: Box::new(["test".into()]),
+            },
+            [],
+        );
+        mock.returns(id, "answer", 42).unwrap();
+
+        (mock, id)
+    }
+
+    fn answer(bus: &DeviceBus, id: Uuid) -> crate::error::Result<i32> {
+        bus.call(Call::<Invoke<'_, i32>>::invoke(id, "answer", &[]))
+            .map(|Return(value)| value)
+    }
+
+    /// Records a list call and an invocation, and returns the path of the transcript.
+    fn record_session(name: &str, id: Uuid, mock: &MockBus) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("oc2-hlapi-{name}-{}.jsonl", process::id()));
+        let bus = DeviceBus::with_transport(Recorder::create(mock.transport(), &path).unwrap());
+
+        assert_eq!(bus.call(Call::list()).unwrap().0.len(), 1);
+        assert_eq!(answer(&bus, id).unwrap(), 42);
+
+        path
+    }
+
+    #[test]
+    fn replay_answers_recorded_calls() {
+        let (mock, id) = mock_with_device();
+        let path = record_session("replay", id, &mock);
+        let bus = DeviceBus::with_transport(Replay::open(&path).unwrap());
+
+        assert_eq!(bus.call(Call::list()).unwrap().0[0].device_id, id);
+        assert_eq!(answer(&bus, id).unwrap(), 42);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_reports_diverging_calls() {
+        let (mock, id) = mock_with_device();
+        let path = record_session("diverge", id, &mock);
+        let bus = DeviceBus::with_transport(Replay::open(&path).unwrap());
+
+        // The transcript starts with a `list` call, not an invocation.
+        let Err(Error::Io(e)) = answer(&bus, id) else {
+            panic!("diverging call didn't fail");
+        };
+
+        assert_eq!(e.kind(), IoErrorKind::InvalidData);
+        let divergence = e.get_ref().unwrap().downcast_ref::<Divergence>().unwrap();
+        assert_eq!(divergence.call, 0);
+        assert_eq!(divergence.expected.as_ref().unwrap()["type"], "list");
+
+        // Every later call fails as well, even one which would have matched.
+        assert!(matches!(bus.call(Call::list()), Err(Error::Io(_))));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    /// A transcript which can't be written to.
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_recording_fails_the_next_call_only() {
+        let (mock, id) = mock_with_device();
+        let bus = DeviceBus::with_transport(Recorder::new(mock.transport(), Broken));
+
+        // The call and its response have passed through by the time recording fails.
+        assert_eq!(answer(&bus, id).unwrap(), 42);
+
+        let Err(Error::Io(e)) = answer(&bus, id) else {
+            panic!("failure to record wasn't reported");
+        };
+        assert_eq!(e.to_string(), "disk full");
+        assert_eq!(mock.calls().len(), 1);
+    }
+}
@@ -0,0 +1,493 @@
+use crate::error::Result;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The terminal attributes changed by [`Transport::configure`], along with the constants and
+/// functions for changing them.
+pub use termios::{self, Termios};
+
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// How a transport waits for its stream to become ready, changed by
+/// [`Transport::configure_poll`]. The read timeout isn't among these, since the bus sets it for
+/// every call.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[non_exhaustive]
+pub struct PollSettings {
+    /// How long a write may wait for the stream to accept more bytes before failing with
+    /// [`IoErrorKind::TimedOut`]. `None`, the default, waits for as long as it takes.
+    pub write_timeout: Option<Duration>,
+}
+
+/// A bidirectional byte stream which HLAPI messages can be sent and received over.
+///
+/// Reads are expected to block until at least one byte is available, and writes are expected to
+/// eventually write the whole buffer. The framing of messages is handled by
+/// [`DeviceBus`](crate::bus::DeviceBus), so a transport only has to move bytes around.
+pub trait Transport: Read + Write {
+    /// Sets how long a read may block for before failing with [`IoErrorKind::TimedOut`] or
+    /// [`IoErrorKind::WouldBlock`]. `None` means that reads block until data is available.
+    ///
+    /// Transports whose reads never block, such as in-memory buffers, can ignore this, which is
+    /// what the default implementation does.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+
+    /// Sets whether reads and writes fail with [`IoErrorKind::WouldBlock`] instead of waiting for
+    /// the stream to become ready.
+    ///
+    /// Transports whose reads and writes never block can ignore this, which is what the default
+    /// implementation does.
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        let _ = nonblocking;
+        Ok(())
+    }
+
+    /// Returns the file descriptor that readiness events for this transport are reported on, if
+    /// there is one. This is what lets a [`DeviceBus`](crate::bus::DeviceBus) be registered with
+    /// an external `mio` event loop.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Changes the terminal attributes of the transport with the given function, if it is a
+    /// terminal, and applies them immediately.
+    ///
+    /// The default implementation fails with [`IoErrorKind::Unsupported`].
+    fn configure(&mut self, f: &mut dyn FnMut(&mut Termios)) -> io::Result<()> {
+        let _ = f;
+        Err(io::Error::new(
+            IoErrorKind::Unsupported,
+            "transport is not a terminal",
+        ))
+    }
+
+    /// Changes how the transport waits for its stream to become ready with the given function.
+    ///
+    /// The default implementation fails with [`IoErrorKind::Unsupported`].
+    fn configure_poll(&mut self, f: &mut dyn FnMut(&mut PollSettings)) -> io::Result<()> {
+        let _ = f;
+        Err(io::Error::new(
+            IoErrorKind::Unsupported,
+            "transport has no poll settings",
+        ))
+    }
+
+    /// Closes the transport, reporting any error which dropping it would have ignored.
+    ///
+    /// The default implementation just drops the transport.
+    fn close(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Replaces the transport with a new connection to the same place, such as after the console
+    /// was reset. Anything that was configured on the old connection, other than the read timeout,
+    /// non-blocking mode and poll settings, has to be configured again.
+    ///
+    /// The default implementation fails with [`IoErrorKind::Unsupported`].
+    fn reopen(&mut self) -> io::Result<()> {
+        Err(io::Error::new(
+            IoErrorKind::Unsupported,
+            "transport can't be reopened",
+        ))
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+
+    fn configure(&mut self, f: &mut dyn FnMut(&mut Termios)) -> io::Result<()> {
+        (**self).configure(f)
+    }
+
+    fn configure_poll(&mut self, f: &mut dyn FnMut(&mut PollSettings)) -> io::Result<()> {
+        (**self).configure_poll(f)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        (*self).close()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        (**self).reopen()
+    }
+}
+
+impl Transport for UnixStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// The serial console that the OC2 VM exposes the HLAPI on, usually `/dev/hvc0`.
+///
+/// The console is put into raw mode while it is open, and its previous terminal attributes are
+/// restored when it is dropped or [closed](Transport::close).
+#[derive(Debug)]
+pub struct Tty {
+    path: PathBuf,
+    console: Console,
+    poll: Poll,
+    events: Events,
+    token: Token,
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+    poll_settings: PollSettings,
+}
+
+impl Tty {
+    /// Opens the console at the specified path and puts it into raw mode.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::open_path(path.as_ref())?)
+    }
+
+    fn open_path(path: &Path) -> io::Result<Self> {
+        let console = open_raw(path)?;
+        let fd = console.as_raw_fd();
+        let token = Token(NEXT_TOKEN.fetch_add(1, Ordering::Relaxed));
+
+        let poll = Poll::new()?;
+        poll.registry().register(
+            &mut SourceFd(&fd),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            console,
+            poll,
+            events: Events::with_capacity(16),
+            token,
+            read_timeout: None,
+            nonblocking: false,
+            poll_settings: PollSettings::default(),
+        })
+    }
+
+    /// Waits until the console is ready for reading or writing, returning `false` if it didn't
+    /// become ready before the timeout.
+    fn wait(&mut self, readable: bool, timeout: Option<Duration>) -> io::Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut timeout = timeout;
+
+        loop {
+            self.events.clear();
+
+            if let Err(e) = self.poll.poll(&mut self.events, timeout) {
+                if e.kind() != IoErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+
+            let ready = self.events.iter().any(|event| {
+                event.token() == self.token
+                    && if readable {
+                        event.is_readable() || event.is_read_closed()
+                    } else {
+                        event.is_writable() || event.is_write_closed()
+                    }
+            });
+
+            if ready {
+                return Ok(true);
+            }
+
+            // Polling again after an interruption or an unrelated event shouldn't restart the
+            // timeout.
+            timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => return Ok(false),
+                },
+                None => None,
+            };
+        }
+    }
+}
+
+/// Opens the console at the specified path and puts it into raw, non-blocking mode.
+pub(crate) fn open_raw(path: &Path) -> io::Result<Console> {
+    // The file itself never blocks, since readiness is only reported when new data arrives.
+    // Reading until the file would block is the only way to be sure that nothing is left over.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+
+    // Sets options to not echo back the input to the device bus, and immediately applies that
+    // change. Without this, writing to the device bus will just hang the applicaton.
+    // Taken from https://docs.rs/miku-rpc/0.1.4/src/miku_rpc/bus.rs.html#34-37
+    let original = Termios::from_fd(fd)?;
+    let mut termios = original;
+    termios::cfmakeraw(&mut termios);
+    termios.c_lflag &= !termios::ECHO;
+    termios::tcsetattr(fd, termios::TCSANOW, &termios)?;
+
+    Ok(Console {
+        file,
+        original: Some(original),
+    })
+}
+
+/// An open console, which gets back the terminal attributes it had before it was opened when it
+/// is dropped. Other programs sharing the console, such as a shell on the VM's serial console,
+/// would break if it was left in raw mode.
+#[derive(Debug)]
+pub(crate) struct Console {
+    pub(crate) file: File,
+    original: Option<Termios>,
+}
+
+impl Console {
+    fn configure(&mut self, f: &mut dyn FnMut(&mut Termios)) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let mut termios = Termios::from_fd(fd)?;
+
+        f(&mut termios);
+        termios::tcsetattr(fd, termios::TCSANOW, &termios)
+    }
+
+    /// Restores the terminal attributes and closes the console, unlike dropping it, reporting
+    /// whether that worked.
+    fn close(mut self) -> io::Result<()> {
+        match self.original.take() {
+            Some(original) => {
+                termios::tcsetattr(self.file.as_raw_fd(), termios::TCSADRAIN, &original)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsRawFd for Console {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Some(original) = self.original.take() {
+            // There is nobody left to report a failure to, and the console is closed right after.
+            let _ = termios::tcsetattr(self.file.as_raw_fd(), termios::TCSANOW, &original);
+        }
+    }
+}
+
+impl Read for Tty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            match self.console.file.read(buf) {
+                Err(e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == IoErrorKind::WouldBlock && !self.nonblocking => {}
+                result => return result,
+            }
+
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+
+            if !self.wait(true, timeout)? {
+                return Err(IoErrorKind::TimedOut.into());
+            }
+        }
+    }
+}
+
+impl Write for Tty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let write_timeout = self.poll_settings.write_timeout;
+        let deadline = write_timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            match self.console.file.write(buf) {
+                Err(e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == IoErrorKind::WouldBlock && !self.nonblocking => {}
+                result => return result,
+            }
+
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+
+            if !self.wait(false, timeout)? {
+                return Err(IoErrorKind::TimedOut.into());
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.console.file.flush()
+    }
+}
+
+impl Transport for Tty {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.console.as_raw_fd())
+    }
+
+    fn configure(&mut self, f: &mut dyn FnMut(&mut Termios)) -> io::Result<()> {
+        self.console.configure(f)
+    }
+
+    fn configure_poll(&mut self, f: &mut dyn FnMut(&mut PollSettings)) -> io::Result<()> {
+        f(&mut self.poll_settings);
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        self.console.close()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        // The old console has to give back its terminal attributes before the new one saves them,
+        // since they belong to the terminal rather than to either file. This usually fails if the
+        // old console was reset, in which case the new one starts out with fresh attributes.
+        let original = self.console.original.take();
+
+        if let Some(original) = &original {
+            let _ = termios::tcsetattr(self.console.as_raw_fd(), termios::TCSANOW, original);
+        }
+
+        let mut tty = Self::open_path(&self.path)?;
+
+        // Whatever the terminal had before the bus first opened it is what it should get back.
+        tty.console.original = original.or(tty.console.original);
+        tty.read_timeout = self.read_timeout;
+        tty.nonblocking = self.nonblocking;
+        tty.poll_settings = self.poll_settings;
+        *self = tty;
+
+        Ok(())
+    }
+}
+
+/// A transport made out of two separate halves, one which is read from and one which is written
+/// to. This is useful for pipes, such as a child process' stdin and stdout, and for in-memory
+/// buffers.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct Duplex<R, W> {
+    /// The half which responses are read from.
+    pub reader: R,
+    /// The half which calls are written to.
+    pub writer: W,
+}
+
+impl<R, W> Duplex<R, W> {
+    /// Creates a new transport from a reader and a writer.
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    /// Splits the transport back into its reader and writer.
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: Read, W> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<R: Read, W: Write> Transport for Duplex<R, W> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    use crate::mock::MockBus;
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    use std::thread;
+
+    #[test]
+    #[cfg(all(feature = "mock", target_os = "linux"))]
+    fn write_times_out_when_console_stops_reading() {
+        let mock = MockBus::new();
+        let tty = mock.tty().unwrap();
+        tty.delay_responses(Duration::from_secs(1));
+
+        let mut console = Tty::open(tty.path()).unwrap();
+        console
+            .configure_poll(&mut |settings| {
+                settings.write_timeout = Some(Duration::from_millis(50));
+            })
+            .unwrap();
+
+        // The mock stops reading while it waits to answer this call.
+        console.write_all(b"\0{\"type\":\"list\"}\0").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        let error = console.write_all(&[b'x'; 1 << 20]).unwrap_err();
+
+        assert_eq!(error.kind(), IoErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
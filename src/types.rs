@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
 
 /// Information associated with an imported file, containing the file's name and size in bytes.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
@@ -45,6 +46,101 @@ pub struct ParameterDescriptor {
     pub ty: Box<str>,
 }
 
+/// A [`DeviceDescriptor`] which borrows its strings from the response it was deserialized from,
+/// where they didn't need unescaping.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceDescriptorRef<'a> {
+    /// The device's UUID.
+    pub device_id: uuid::Uuid,
+    /// A list of strings that determine which kind of device the UUID refers to.
+    #[serde(borrow, deserialize_with = "borrow_strs")]
+    pub type_names: Vec<Cow<'a, str>>,
+}
+
+/// Deserializes a list of strings, borrowing each one which doesn't need unescaping. Serde only
+/// borrows a `Cow` which is a field by itself, rather than inside a `Vec`.
+fn borrow_strs<'de: 'a, 'a, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Cow<'a, str>>, D::Error> {
+    #[derive(Deserialize)]
+    struct Str<'a>(#[serde(borrow)] Cow<'a, str>);
+
+    let strs = Vec::<Str<'a>>::deserialize(deserializer)?;
+    Ok(strs.into_iter().map(|s| s.0).collect())
+}
+
+impl DeviceDescriptorRef<'_> {
+    /// Copies the descriptor's strings, so that it no longer borrows from the response.
+    pub fn into_owned(self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            device_id: self.device_id,
+            type_names: self.type_names.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// A [`MethodDescriptor`] which borrows its strings from the response it was deserialized from,
+/// where they didn't need unescaping.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodDescriptorRef<'a> {
+    /// The method's name.
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    /// The method's return type.
+    #[serde(borrow)]
+    pub return_type: Cow<'a, str>,
+    /// Documentation about what the method does.
+    #[serde(borrow)]
+    pub description: Cow<'a, str>,
+    /// A list of method parameters.
+    #[serde(borrow)]
+    pub parameters: Vec<ParameterDescriptorRef<'a>>,
+}
+
+impl MethodDescriptorRef<'_> {
+    /// Copies the descriptor's strings, so that it no longer borrows from the response.
+    pub fn into_owned(self) -> MethodDescriptor {
+        MethodDescriptor {
+            name: self.name.into(),
+            return_type: self.return_type.into(),
+            description: self.description.into(),
+            parameters: self
+                .parameters
+                .into_iter()
+                .map(ParameterDescriptorRef::into_owned)
+                .collect(),
+        }
+    }
+}
+
+/// A [`ParameterDescriptor`] which borrows its strings from the response it was deserialized from,
+/// where they didn't need unescaping.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct ParameterDescriptorRef<'a> {
+    /// The parameter's name.
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    /// Documentation about what the parameter is used for.
+    #[serde(borrow)]
+    pub description: Cow<'a, str>,
+    #[serde(rename = "type", borrow)]
+    /// The parameter's type.
+    pub ty: Cow<'a, str>,
+}
+
+impl ParameterDescriptorRef<'_> {
+    /// Copies the descriptor's strings, so that it no longer borrows from the response.
+    pub fn into_owned(self) -> ParameterDescriptor {
+        ParameterDescriptor {
+            name: self.name.into(),
+            description: self.description.into(),
+            ty: self.ty.into(),
+        }
+    }
+}
+
 /// A block's relative direction.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]